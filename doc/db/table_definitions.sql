@@ -6,7 +6,8 @@ CREATE TABLE IF NOT EXISTS packages (
     version         TEXT NOT NULL,
     real_version    INTEGER NOT NULL,
     description     TEXT,
-    hash            TEXT
+    hash            TEXT,
+    hash_algorithm  TEXT NOT NULL DEFAULT 'md5'
 );
 
 CREATE TABLE IF NOT EXISTS dependencies (
@@ -24,7 +25,15 @@ CREATE TABLE IF NOT EXISTS fsentries (
     parent          INTEGER,
     package         INTEGER NOT NULL,
     name            TEXT NOT NULL,
+    type            TEXT,
     hash            TEXT,
+    hash_algorithm  TEXT,
+    mode            INTEGER,
+    uid             INTEGER,
+    gid             INTEGER,
+    mtime           INTEGER,
+    link_target     TEXT,
+    rdev            INTEGER,
 
     UNIQUE (parent, package, name),
     FOREIGN KEY (package) REFERENCES packages(id) ON UPDATE CASCADE ON DELETE CASCADE,
@@ -1,8 +1,15 @@
 use super::Config;
-use crate::{db::DBConnection, error::LError, mirror::Mirror, package::*, *};
+use crate::{
+    db::DBConnection,
+    error::{LError, LErrorClass},
+    mirror::Mirror,
+    package::{local::LocalPackage, *},
+    *,
+};
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
 use threadpool::ThreadPool;
 
 /// Updates the mirrors in the provided config
@@ -54,7 +61,10 @@ pub fn install(
     load_mirrors(config, mirrors)?;
 
     //Create a database connection for looking up already installed packages
-    let mut db_con = DBConnection::open(&config.get_config_dir().join("installed.db"))?;
+    let mut db_con = DBConnection::open(
+        &config.get_config_dir().join("installed.db"),
+        config.get_root(),
+    )?;
 
     // Resolve dependencies into the pool
     for package in packages {
@@ -67,6 +77,146 @@ pub fn install(
         )?;
     }
 
+    install_pool(config, pool, &mut db_con)
+}
+
+/// Installs the supplied local `.lfpkg` files without needing them to be available on a mirror
+///
+/// The packages are built from their embedded manifests, only the dependencies
+/// that are neither installed nor supplied as files are resolved from the mirrors.
+///
+/// Files of packages that are already installed are skipped if they are the installed
+/// package, else this fails with `LErrorClass::PackageInstalled`: leaf can't replace packages yet
+/// # Arguments
+/// * `config` - The configuration to use
+/// * `files` - The paths to the `.lfpkg` files to install
+/// * `mirrors` - The mirrors to search for missing dependencies
+/// * `pool` - A pool to resolve all the packages into
+pub fn install_local(
+    config: &Config,
+    files: &[PathBuf],
+    mirrors: &mut [Mirror],
+    pool: &mut Vec<PackageRef>,
+) -> Result<(), LError> {
+    util::ensure_dirs(config)?;
+
+    // The mirrors are only needed if dependencies are missing, so a mirror without a cache is fine
+    for mirror in mirrors.iter_mut() {
+        if let Err(e) = mirror.load(config) {
+            warn!("Could not load mirror {}: {}", mirror.name, e);
+        }
+    }
+
+    //Create a database connection for looking up already installed packages
+    let mut db_con = DBConnection::open(
+        &config.get_config_dir().join("installed.db"),
+        config.get_root(),
+    )?;
+
+    let mut packages: Vec<PackageRef> = Vec::new();
+    let transaction = db_con.new_transaction()?;
+    for file in files {
+        let package = LocalPackage::from_file(file)?;
+
+        // The installed package would be used instead of the file, so tell the user
+        if let Some(hash) = transaction.get_package_hash(&package.get_name())? {
+            if util::hash::verify_file(file, &hash)? {
+                usermsg!("Package {} is already installed", package.get_fq_name());
+                continue;
+            }
+
+            let e = LError::new(
+                LErrorClass::PackageInstalled,
+                &format!(
+                    "Can't install {} from {}: Another build of {} is installed, remove it first",
+                    package.get_fq_name(),
+                    file.to_string_lossy(),
+                    package.get_name()
+                ),
+            );
+            usererr!("{}", e);
+            return Err(e);
+        }
+
+        debug!(
+            "Using local package {} from {}",
+            package.get_fq_name(),
+            file.to_string_lossy()
+        );
+        packages.push(Arc::new(RwLock::new(PackageVariant::Local(package))));
+    }
+    drop(transaction);
+
+    // Resolve the dependencies into the pool
+    let mut resolved: Vec<String> = Vec::new();
+    for package in &packages {
+        resolve_local(
+            package.clone(),
+            &packages,
+            &mut resolved,
+            pool,
+            mirrors,
+            &mut db_con,
+        )?;
+    }
+
+    install_pool(config, pool, &mut db_con)
+}
+
+/// Resolves the supplied local package into the pool, making sure the local
+/// packages it depends on get resolved before so they are not searched on the mirrors
+/// # Arguments
+/// * `package` - The package to resolve
+/// * `packages` - All the local packages to install
+/// * `resolved` - The names of the local packages that have already been resolved
+/// * `pool` - The pool to resolve the packages into
+/// * `mirrors` - The mirrors to search for missing dependencies
+/// * `db_con` - The database connection to use for looking up installed packages
+fn resolve_local(
+    package: PackageRef,
+    packages: &[PackageRef],
+    resolved: &mut Vec<String>,
+    pool: &mut Vec<PackageRef>,
+    mirrors: &[Mirror],
+    db_con: &mut DBConnection,
+) -> Result<(), LError> {
+    let name = package.get_name();
+    if resolved.contains(&name) {
+        return Ok(());
+    }
+    resolved.push(name);
+
+    let dependencies = package
+        .read()
+        .expect("Lock package mutex")
+        .get_dependencies()
+        .get_unresolved()?
+        .clone();
+    for dependency in dependencies {
+        if let Some(local) = packages.iter().find(|p| p.get_name() == dependency) {
+            resolve_local(local.clone(), packages, resolved, pool, mirrors, db_con)?;
+        }
+    }
+
+    crate::util::dependencies::resolve_dependencies(
+        package,
+        pool,
+        mirrors,
+        &mut db_con.new_transaction()?,
+    )
+}
+
+/// Downloads the packages in the pool that are not available locally, installs them
+/// and runs the triggers the installed files activated, even if installing a package fails
+/// # Arguments
+/// * `config` - The configuration to use
+/// * `pool` - The pool of resolved packages to install
+/// * `db_con` - The database connection to use for installing
+fn install_pool(
+    config: &Config,
+    pool: &mut [PackageRef],
+    db_con: &mut DBConnection,
+) -> Result<(), LError> {
     // Download the packages and update the pool
     let results = download_packages(config, pool);
     for result in results {
@@ -88,11 +238,13 @@ pub fn install(
     }
 
     // Now install the packages one after the other
-    for package_ref in pool {
-        util::transaction::install_package(package_ref.clone(), config, &mut db_con)?;
-    }
+    let mut triggers = util::triggers::PendingTriggers::new(config)?;
+    let res = pool.iter().try_for_each(|package_ref| {
+        util::transaction::install_package(package_ref.clone(), config, db_con, &mut triggers)
+    });
 
-    Ok(())
+    // And run the triggers the installed files activated once, even if a package failed
+    triggers.run_after(config, res)
 }
 
 /// Loads the cached mirror file of every mirror
@@ -109,7 +261,7 @@ pub fn load_mirrors(config: &Config, mirrors: &mut [Mirror]) -> Result<(), LErro
 
 pub fn download_packages(
     config: &Config,
-    packages: &Vec<PackageRef>,
+    packages: &[PackageRef],
 ) -> Vec<Result<PackageRef, LError>> {
     let pool = ThreadPool::new(config.download_workers);
     type Return = Vec<Result<PackageRef, LError>>;
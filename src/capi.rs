@@ -0,0 +1,253 @@
+//! The functions leaf exposes to C frontends
+//!
+//! The functions operate on a handle created using `leaf_new()` and use its config.
+//! They return `0` on success and `-1` on failure, errors get logged. Panics never
+//! unwind into the caller, they are caught and reported as failures.
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::error::*;
+use crate::Leaf;
+
+/// The callback `leaf_keyring_list()` calls for every key
+/// # Arguments
+/// * `name` - The name of the key
+/// * `key` - The hex encoded public key
+/// * `user` - The user data passed to `leaf_keyring_list()`
+pub type LeafKeyCallback =
+    extern "C" fn(name: *const c_char, key: *const c_char, user: *mut c_void);
+
+/// Converts the supplied C string to a Rust string
+/// # Arguments
+/// * `s` - The C string
+/// * `what` - A description of the string for messages
+///
+/// # Safety
+/// `s` must be NULL or point to a valid NUL terminated string
+unsafe fn to_str<'a>(s: *const c_char, what: &str) -> Result<&'a str, LError> {
+    if s.is_null() {
+        return Err(LError::new(
+            LErrorClass::Unknown,
+            &format!("{} is NULL", what),
+        ));
+    }
+
+    CStr::from_ptr(s).to_str().map_err(|e| {
+        LError::new(
+            LErrorClass::Unknown,
+            &format!("{} is not valid UTF-8: {}", what, e),
+        )
+    })
+}
+
+/// Converts the result of an operation to the return value of the C API, logging errors
+/// # Arguments
+/// * `res` - The result of the operation
+fn to_c(res: Result<(), LError>) -> c_int {
+    match res {
+        Ok(_) => 0,
+        Err(e) => {
+            error!("{}", e);
+            -1
+        }
+    }
+}
+
+/// Runs the supplied operation on the handle `leaf` and converts its result to the return value
+/// of the C API, catching panics as unwinding into C is undefined behaviour
+/// # Arguments
+/// * `leaf` - The handle to operate on
+/// * `op` - The operation to run
+///
+/// # Safety
+/// `leaf` must be NULL or a handle returned by `leaf_new()`
+unsafe fn with_leaf<F>(leaf: *mut Leaf, op: F) -> c_int
+where
+    F: FnOnce(&mut Leaf) -> Result<(), LError>,
+{
+    let res = catch_unwind(AssertUnwindSafe(|| match leaf.as_mut() {
+        Some(leaf) => op(leaf),
+        None => Err(LError::new(LErrorClass::Unknown, "leaf is NULL")),
+    }));
+
+    match res {
+        Ok(res) => to_c(res),
+        Err(_) => {
+            error!("Caught a panic in the C API");
+            -1
+        }
+    }
+}
+
+/// Creates a new handle using the default config and no mirrors,
+/// returns NULL on failure. The handle has to be freed using `leaf_free()`
+#[no_mangle]
+pub extern "C" fn leaf_new() -> *mut Leaf {
+    match catch_unwind(|| Box::new(Leaf::new(Vec::new()))) {
+        Ok(leaf) => Box::into_raw(leaf),
+        Err(_) => {
+            error!("Caught a panic in the C API");
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Frees the supplied handle, NULL is ignored
+///
+/// # Safety
+/// `leaf` must be NULL or a handle returned by `leaf_new()` that has not been freed yet
+#[no_mangle]
+pub unsafe extern "C" fn leaf_free(leaf: *mut Leaf) {
+    if !leaf.is_null() {
+        // Dropping the handle must not unwind into the caller either
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(leaf))));
+    }
+}
+
+/// Calls `callback` for every key in the keyring of `leaf`
+///
+/// # Safety
+/// `leaf` must be a handle returned by `leaf_new()`
+#[no_mangle]
+pub unsafe extern "C" fn leaf_keyring_list(
+    leaf: *mut Leaf,
+    callback: LeafKeyCallback,
+    user: *mut c_void,
+) -> c_int {
+    with_leaf(leaf, |leaf| {
+        for key in leaf.get_keys()? {
+            let name = CString::new(key.name.as_str()).unwrap_or_default();
+            let value = CString::new(key.key.as_str()).unwrap_or_default();
+            callback(name.as_ptr(), value.as_ptr(), user);
+        }
+        Ok(())
+    })
+}
+
+/// Imports the hex encoded public key `key` as `name` into the keyring of `leaf`
+///
+/// # Safety
+/// `leaf` must be a handle returned by `leaf_new()`, the strings must be valid and NUL terminated
+#[no_mangle]
+pub unsafe extern "C" fn leaf_keyring_import(
+    leaf: *mut Leaf,
+    name: *const c_char,
+    key: *const c_char,
+) -> c_int {
+    with_leaf(leaf, |leaf| {
+        leaf.import_key(to_str(name, "name")?, to_str(key, "key")?)
+    })
+}
+
+/// Removes the key `name` from the keyring of `leaf`
+///
+/// # Safety
+/// `leaf` must be a handle returned by `leaf_new()`, the strings must be valid and NUL terminated
+#[no_mangle]
+pub unsafe extern "C" fn leaf_keyring_remove(leaf: *mut Leaf, name: *const c_char) -> c_int {
+    with_leaf(leaf, |leaf| leaf.remove_key(to_str(name, "name")?))
+}
+
+/// Marks the key `name` of the keyring of `leaf` as trusted for the mirror `mirror`
+///
+/// # Safety
+/// `leaf` must be a handle returned by `leaf_new()`, the strings must be valid and NUL terminated
+#[no_mangle]
+pub unsafe extern "C" fn leaf_keyring_trust(
+    leaf: *mut Leaf,
+    name: *const c_char,
+    mirror: *const c_char,
+) -> c_int {
+    with_leaf(leaf, |leaf| {
+        leaf.trust_key(to_str(name, "name")?, to_str(mirror, "mirror")?)
+    })
+}
+
+/// Revokes the trust of the key `name` of the keyring of `leaf` for the mirror `mirror`
+///
+/// # Safety
+/// `leaf` must be a handle returned by `leaf_new()`, the strings must be valid and NUL terminated
+#[no_mangle]
+pub unsafe extern "C" fn leaf_keyring_untrust(
+    leaf: *mut Leaf,
+    name: *const c_char,
+    mirror: *const c_char,
+) -> c_int {
+    with_leaf(leaf, |leaf| {
+        leaf.untrust_key(to_str(name, "name")?, to_str(mirror, "mirror")?)
+    })
+}
+
+/// Marks the key `name` of the keyring of `leaf` as trusted for verifying packages
+///
+/// # Safety
+/// `leaf` must be a handle returned by `leaf_new()`, the strings must be valid and NUL terminated
+#[no_mangle]
+pub unsafe extern "C" fn leaf_keyring_trust_packages(
+    leaf: *mut Leaf,
+    name: *const c_char,
+) -> c_int {
+    with_leaf(leaf, |leaf| {
+        leaf.trust_key_for_packages(to_str(name, "name")?)
+    })
+}
+
+/// Revokes the trust of the key `name` of the keyring of `leaf` for verifying packages
+///
+/// # Safety
+/// `leaf` must be a handle returned by `leaf_new()`, the strings must be valid and NUL terminated
+#[no_mangle]
+pub unsafe extern "C" fn leaf_keyring_untrust_packages(
+    leaf: *mut Leaf,
+    name: *const c_char,
+) -> c_int {
+    with_leaf(leaf, |leaf| {
+        leaf.untrust_key_for_packages(to_str(name, "name")?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+
+    extern "C" fn count(_: *const c_char, _: *const c_char, user: *mut c_void) {
+        unsafe { *(user as *mut usize) += 1 };
+    }
+
+    #[test]
+    fn keyring_functions_use_the_config_of_the_handle() {
+        let dir = tempfile::tempdir().unwrap();
+        let leaf = leaf_new();
+        unsafe { (*leaf).config.keyring_dir = Some(dir.path().to_path_buf()) };
+
+        let name = CString::new("test").unwrap();
+        let key = CString::new(KEY).unwrap();
+        let mut keys: usize = 0;
+        unsafe {
+            assert_eq!(leaf_keyring_import(leaf, name.as_ptr(), key.as_ptr()), 0);
+            assert_eq!(leaf_keyring_trust_packages(leaf, name.as_ptr()), 0);
+            assert_eq!(
+                leaf_keyring_list(leaf, count, &mut keys as *mut usize as *mut c_void),
+                0
+            );
+            leaf_free(leaf);
+        }
+
+        assert_eq!(keys, 1);
+        assert!(dir.path().join("test.pub").exists());
+    }
+
+    #[test]
+    fn invalid_arguments_fail() {
+        let name = CString::new("test").unwrap();
+        unsafe {
+            assert_eq!(leaf_keyring_remove(std::ptr::null_mut(), name.as_ptr()), -1);
+
+            let leaf = leaf_new();
+            assert_eq!(leaf_keyring_remove(leaf, std::ptr::null()), -1);
+            leaf_free(leaf);
+        }
+    }
+}
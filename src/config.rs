@@ -2,12 +2,14 @@ pub mod callbacks;
 pub mod config_file;
 pub mod dirs;
 pub mod files;
+pub mod keyring;
 
 use callbacks::*;
 use serde::Deserialize;
 use std::path::PathBuf;
 
 use crate::mirror::Mirror;
+use crate::util::triggers::Trigger;
 pub use config_file::*;
 
 /// Represents the contents of the config file
@@ -31,6 +33,13 @@ pub struct Config {
     #[serde(default = "default_download_workers")]
     pub download_workers: usize,
 
+    /// How often failed downloads should be retried (default: `3`)
+    pub download_retries: Option<u32>,
+
+    /// How many milliseconds to wait before retrying a failed download,
+    /// doubling with every further retry (default: `1000`)
+    pub download_retry_delay: Option<u64>,
+
     /// If a progress bar should be rendered or not
     #[serde(default = "default_render_bar")]
     pub render_bar: bool,
@@ -38,6 +47,25 @@ pub struct Config {
     /// If leaf should work aggressively (overwrite files, etc...)
     pub force: Option<bool>,
 
+    /// If leaf should deploy packages directly from their archive instead of
+    /// extracting them to the `packages_dir` first (default: `false`)
+    pub stream_deploy: Option<bool>,
+
+    /// If leaf should sync deployed files to disk before renaming them into place (default: `true`)
+    pub fsync: Option<bool>,
+
+    /// How leaf should place files from the package cache into the root (default: `copy`),
+    /// ignored if `stream_deploy` is set as there is no package cache to place from
+    #[serde(default)]
+    pub deploy_strategy: DeployStrategy,
+
+    /// If leaf should deploy packages that are unsigned or can't be verified using the keyring (default: `false`)
+    pub allow_unsigned_packages: Option<bool>,
+
+    /// The triggers to run once per transaction if a deployed path matches
+    #[serde(default, rename(deserialize = "trigger"))]
+    pub triggers: Vec<Trigger>,
+
     /// The root directory leaf should work on (default: `/`)
     pub root: Option<PathBuf>,
 
@@ -47,6 +75,8 @@ pub struct Config {
     pub config_file: Option<PathBuf>,
     /// The directory leaf should look for and store the mirror files (default: `/etc/leaf/mirrors/`)
     pub mirrors_dir: Option<PathBuf>,
+    /// The directory leaf should look for the trusted public keys in (default: `/etc/leaf/keyring/`)
+    pub keyring_dir: Option<PathBuf>,
 
     /// The directory to search for lib files (deafult files) (default: `/lib/leaf`)
     pub lib_dir: Option<PathBuf>,
@@ -67,12 +97,20 @@ impl Default for Config {
         Config {
             loglevel: LogLevel::Default,
             download_workers: 5,
+            download_retries: None,
+            download_retry_delay: None,
             render_bar: true,
             force: None,
+            stream_deploy: None,
+            fsync: None,
+            deploy_strategy: DeployStrategy::default(),
+            allow_unsigned_packages: None,
+            triggers: Vec::new(),
             root: None,
             config_dir: None,
             config_file: None,
             mirrors_dir: None,
+            keyring_dir: None,
             lib_dir: None,
             cache_dir: None,
             download_dir: None,
@@ -96,6 +134,24 @@ pub enum LogLevel {
     Ultraverbose,
 }
 
+/// The ways leaf can place files from the package cache into the root
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Deserialize)]
+pub enum DeployStrategy {
+    /// Copy every file
+    #[default]
+    #[serde(rename(deserialize = "copy"))]
+    Copy,
+    /// Hard link every file, falling back to copying if the root is on another filesystem
+    /// or the metadata of the cached file differs from the package.
+    /// Note that modifying a deployed file modifies the package cache, too
+    #[serde(rename(deserialize = "hardlink"))]
+    HardLink,
+    /// Reflink (clone) every file where supported by the filesystem, falling back to copying
+    #[serde(rename(deserialize = "reflink"))]
+    Reflink,
+}
+
 /// Provides a default for the `download_workers` field
 fn default_download_workers() -> usize {
     5
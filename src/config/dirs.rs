@@ -32,6 +32,16 @@ impl Config {
         }
     }
 
+    /// Returns the directory leaf should look for the trusted public keys in (overrides the `config_dir` default)
+    ///
+    /// Default: `config_dir/keyring`
+    pub fn get_keyring_dir(&self) -> PathBuf {
+        match &self.keyring_dir {
+            Some(p) => PathBuf::from(p),
+            None => self.get_config_dir().join("keyring"),
+        }
+    }
+
     /// Returns the directory leaf uses for lib files (overrides the `root_dir` default)
     ///
     /// Default: `root_dir/lib/leaf`
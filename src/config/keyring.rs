@@ -0,0 +1,341 @@
+//! The keyring holds the public keys leaf trusts for verifying signatures
+//!
+//! Every key is stored as `<name>.pub` in the keyring directory. A key is trusted for
+//! nothing by default: It has to be trusted for verifying packages and for the package
+//! lists of specific mirrors explicitly, which gets recorded in `trust.toml` in the keyring directory.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use super::Config;
+use crate::error::*;
+use crate::util::signature;
+
+/// The file name of the file recording what keys are trusted for
+const TRUST_FILE: &str = "trust.toml";
+
+/// A public key in the keyring
+#[derive(Clone, Debug, PartialEq)]
+pub struct Key {
+    /// The name of the key, its file name without `.pub`
+    pub name: String,
+    /// The hex encoded Ed25519 public key
+    pub key: String,
+    /// If this key is trusted for verifying packages
+    pub packages: bool,
+    /// The mirrors this key is trusted for
+    pub mirrors: Vec<String>,
+}
+
+/// The contents of the trust file
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct TrustFile {
+    /// The names of the keys trusted for packages
+    #[serde(default)]
+    packages: Vec<String>,
+    /// The names of the mirrors by the names of the keys trusted for them
+    #[serde(default)]
+    keys: BTreeMap<String, Vec<String>>,
+}
+
+/// The keys leaf trusts, stored in a keyring directory
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    dir: PathBuf,
+    keys: Vec<Key>,
+}
+
+impl Config {
+    /// Loads the keyring from the keyring directory
+    pub fn get_keyring(&self) -> Result<Keyring, LError> {
+        Keyring::load(&self.get_keyring_dir())
+    }
+}
+
+impl Keyring {
+    /// Loads the keyring stored in the supplied directory, a missing directory is an empty keyring
+    /// # Arguments
+    /// * `dir` - The keyring directory
+    pub fn load(dir: &Path) -> Result<Self, LError> {
+        let context = format!("When loading keyring from {}", dir.to_string_lossy());
+        let mut keyring = Self {
+            dir: dir.to_path_buf(),
+            keys: Vec::new(),
+        };
+
+        if !dir.exists() {
+            return Ok(keyring);
+        }
+
+        let trust_path = dir.join(TRUST_FILE);
+        let mut trust: TrustFile = match trust_path.exists() {
+            true => {
+                let data = std::fs::read_to_string(&trust_path).err_prepend(&context)?;
+                toml::from_str(&data)
+                    .map_err(LError::from)
+                    .err_prepend(&context)?
+            }
+            false => TrustFile::default(),
+        };
+
+        for entry in std::fs::read_dir(dir).err_prepend(&context)? {
+            let path = entry.err_prepend(&context)?.path();
+            if path.extension().map(|e| e != "pub").unwrap_or(true) {
+                continue;
+            }
+
+            let name = path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            let key = std::fs::read_to_string(&path)
+                .err_prepend(&context)?
+                .trim()
+                .to_string();
+            let packages = trust.packages.contains(&name);
+            let mirrors = trust.keys.remove(&name).unwrap_or_default();
+
+            keyring.keys.push(Key {
+                name,
+                key,
+                packages,
+                mirrors,
+            });
+        }
+
+        keyring.keys.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(keyring)
+    }
+
+    /// Returns all keys in the keyring, sorted by name
+    pub fn list(&self) -> &[Key] {
+        &self.keys
+    }
+
+    /// Returns the key with the supplied name
+    /// # Arguments
+    /// * `name` - The name of the key
+    pub fn get(&self, name: &str) -> Option<&Key> {
+        self.keys.iter().find(|k| k.name == name)
+    }
+
+    /// Returns the hex encoded public keys trusted for verifying packages
+    pub fn get_package_keys(&self) -> Vec<String> {
+        self.keys
+            .iter()
+            .filter(|k| k.packages)
+            .map(|k| k.key.clone())
+            .collect()
+    }
+
+    /// Returns the hex encoded public keys trusted for the package list of the supplied mirror
+    /// # Arguments
+    /// * `mirror` - The name of the mirror
+    pub fn get_mirror_keys(&self, mirror: &str) -> Vec<String> {
+        self.keys
+            .iter()
+            .filter(|k| k.mirrors.iter().any(|m| m == mirror))
+            .map(|k| k.key.clone())
+            .collect()
+    }
+
+    /// Imports a new key into the keyring and persists it
+    /// # Arguments
+    /// * `name` - The name to store the key under
+    /// * `key` - The hex encoded Ed25519 public key
+    pub fn import(&mut self, name: &str, key: &str) -> Result<(), LError> {
+        let context = format!("When importing key {}", name);
+
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(LError::new(
+                LErrorClass::Keyring,
+                &format!("{}: Invalid key name", context),
+            ));
+        }
+        if self.get(name).is_some() {
+            return Err(LError::new(
+                LErrorClass::Keyring,
+                &format!("{}: A key with this name already exists", context),
+            ));
+        }
+
+        let key = key.trim().to_lowercase();
+        signature::parse_public_key(&key).err_prepend(&context)?;
+
+        std::fs::create_dir_all(&self.dir).err_prepend(&context)?;
+        std::fs::write(self.key_path(name), format!("{}\n", key)).err_prepend(&context)?;
+
+        self.keys.push(Key {
+            name: name.to_string(),
+            key,
+            packages: false,
+            mirrors: Vec::new(),
+        });
+        self.keys.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(())
+    }
+
+    /// Removes the key with the supplied name from the keyring, including its trust
+    /// # Arguments
+    /// * `name` - The name of the key
+    pub fn remove(&mut self, name: &str) -> Result<(), LError> {
+        let context = format!("When removing key {}", name);
+        let index = self.index_of(name).err_prepend(&context)?;
+
+        std::fs::remove_file(self.key_path(name)).err_prepend(&context)?;
+        self.keys.remove(index);
+
+        self.save_trust().err_prepend(&context)
+    }
+
+    /// Marks the key with the supplied name as trusted for the package list of a mirror
+    /// # Arguments
+    /// * `name` - The name of the key
+    /// * `mirror` - The name of the mirror
+    pub fn trust(&mut self, name: &str, mirror: &str) -> Result<(), LError> {
+        let context = format!("When trusting key {} for mirror {}", name, mirror);
+        let index = self.index_of(name).err_prepend(&context)?;
+
+        let mirrors = &mut self.keys[index].mirrors;
+        if !mirrors.iter().any(|m| m == mirror) {
+            mirrors.push(mirror.to_string());
+            mirrors.sort();
+        }
+
+        self.save_trust().err_prepend(&context)
+    }
+
+    /// Revokes the trust of the key with the supplied name for the package list of a mirror
+    /// # Arguments
+    /// * `name` - The name of the key
+    /// * `mirror` - The name of the mirror
+    pub fn untrust(&mut self, name: &str, mirror: &str) -> Result<(), LError> {
+        let context = format!("When untrusting key {} for mirror {}", name, mirror);
+        let index = self.index_of(name).err_prepend(&context)?;
+
+        self.keys[index].mirrors.retain(|m| m != mirror);
+
+        self.save_trust().err_prepend(&context)
+    }
+
+    /// Marks the key with the supplied name as trusted for verifying packages
+    /// # Arguments
+    /// * `name` - The name of the key
+    pub fn trust_packages(&mut self, name: &str) -> Result<(), LError> {
+        let context = format!("When trusting key {} for packages", name);
+        let index = self.index_of(name).err_prepend(&context)?;
+
+        self.keys[index].packages = true;
+
+        self.save_trust().err_prepend(&context)
+    }
+
+    /// Revokes the trust of the key with the supplied name for verifying packages
+    /// # Arguments
+    /// * `name` - The name of the key
+    pub fn untrust_packages(&mut self, name: &str) -> Result<(), LError> {
+        let context = format!("When untrusting key {} for packages", name);
+        let index = self.index_of(name).err_prepend(&context)?;
+
+        self.keys[index].packages = false;
+
+        self.save_trust().err_prepend(&context)
+    }
+
+    /// Returns the path the key with the supplied name is stored at
+    /// # Arguments
+    /// * `name` - The name of the key
+    fn key_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.pub", name))
+    }
+
+    /// Returns the index of the key with the supplied name
+    /// # Arguments
+    /// * `name` - The name of the key
+    fn index_of(&self, name: &str) -> Result<usize, LError> {
+        self.keys
+            .iter()
+            .position(|k| k.name == name)
+            .ok_or_else(|| {
+                LError::new(
+                    LErrorClass::KeyNotFound,
+                    &format!("There is no key named {}", name),
+                )
+            })
+    }
+
+    /// Writes the trust of all keys to the trust file
+    fn save_trust(&self) -> Result<(), LError> {
+        let trust = TrustFile {
+            packages: self
+                .keys
+                .iter()
+                .filter(|k| k.packages)
+                .map(|k| k.name.clone())
+                .collect(),
+            keys: self
+                .keys
+                .iter()
+                .filter(|k| !k.mirrors.is_empty())
+                .map(|k| (k.name.clone(), k.mirrors.clone()))
+                .collect(),
+        };
+
+        let data = toml::to_string(&trust).map_err(|e| {
+            LError::new(
+                LErrorClass::TOML,
+                &format!("When serializing trust file: {}", e),
+            )
+        })?;
+
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.dir.join(TRUST_FILE), data)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    const OTHER_KEY: &str = "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c";
+
+    #[test]
+    fn imported_keys_are_not_trusted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut keyring = Keyring::load(dir.path()).unwrap();
+
+        keyring.import("test", KEY).unwrap();
+
+        assert!(keyring.get_package_keys().is_empty());
+        assert!(keyring.get_mirror_keys("mirror").is_empty());
+    }
+
+    #[test]
+    fn package_and_mirror_trust_are_separate() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut keyring = Keyring::load(dir.path()).unwrap();
+        keyring.import("packages", KEY).unwrap();
+        keyring.import("mirror", OTHER_KEY).unwrap();
+
+        keyring.trust_packages("packages").unwrap();
+        keyring.trust("mirror", "mirror").unwrap();
+
+        // The trust is persisted
+        let mut keyring = Keyring::load(dir.path()).unwrap();
+        assert_eq!(keyring.get_package_keys(), vec![KEY.to_string()]);
+        assert_eq!(
+            keyring.get_mirror_keys("mirror"),
+            vec![OTHER_KEY.to_string()]
+        );
+
+        keyring.untrust_packages("packages").unwrap();
+        let keyring = Keyring::load(dir.path()).unwrap();
+        assert!(keyring.get_package_keys().is_empty());
+    }
+}
@@ -26,7 +26,8 @@ impl DBConnection {
     /// Creates a new connection opening the supplied file
     /// # Arguments
     /// * `path` - The path to read from
-    pub fn open(path: &Path) -> Result<DBConnection, LError> {
+    /// * `root` - The root directory the packages are deployed to, used for upgrading the database
+    pub fn open(path: &Path, root: &Path) -> Result<DBConnection, LError> {
         let connection = Connection::open_with_flags(
             path,
             OpenFlags::SQLITE_OPEN_READ_WRITE
@@ -35,7 +36,7 @@ impl DBConnection {
         )?;
 
         let con = DBConnection { connection };
-        con.ensure_tables()?;
+        con.ensure_tables(root)?;
 
         Ok(con)
     }
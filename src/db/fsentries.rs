@@ -1,8 +1,22 @@
-use crate::util::fs::FSEntry;
+use crate::util::{self, fs::FSEntry};
+use rusqlite::params;
 
 use super::*;
 
 impl<'a> DBTransaction<'a> {
+    /// Removes all files owned by the supplied package
+    ///
+    /// # Arguments
+    /// * `pkgid` - The package to remove the files of
+    pub fn remove_files(&self, pkgid: i64) -> Result<(), LError> {
+        let removed = self
+            .transaction
+            .execute("DELETE FROM fsentries WHERE package = ?", [pkgid])?;
+        trace!("Removed {} fsentries of package {}", removed, pkgid);
+
+        Ok(())
+    }
+
     /// Adds the supplied files to the parent owned by the supplied package
     ///
     /// # Arguments
@@ -15,17 +29,33 @@ impl<'a> DBTransaction<'a> {
         parent: Option<i64>,
         files: &[FSEntry],
     ) -> Result<(), LError> {
-        let mut stmt = self
-            .transaction
-            .prepare("INSERT INTO fsentries (name, package, parent, hash) VALUES (?, ?, ?, ?)")?;
+        let mut stmt = self.transaction.prepare(
+            "INSERT INTO fsentries (name, package, parent, type, hash, hash_algorithm, mode, uid, gid, mtime, link_target, rdev)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
 
         for file in files {
             trace!("Inserting fsentry {}", &file.name);
-            let parent = Some(stmt.insert([
-                Some(file.name.clone()),
-                Some(pkgid.to_string()),
-                parent.map(|p| p.to_string()),
-                file.hash.clone(),
+            let (algorithm, digest) = match &file.hash {
+                Some(hash) => {
+                    let (algorithm, digest) = util::hash::split_hash(hash)?;
+                    (Some(algorithm.as_str()), Some(digest))
+                }
+                None => (None, None),
+            };
+            let parent = Some(stmt.insert(params![
+                file.name,
+                pkgid,
+                parent,
+                file.entry_type.as_str(),
+                digest,
+                algorithm,
+                file.mode,
+                file.uid,
+                file.gid,
+                file.mtime,
+                file.link_target,
+                file.rdev as i64,
             ])?);
             self.insert_files(pkgid, parent, &file.children)?;
         }
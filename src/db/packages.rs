@@ -1,5 +1,5 @@
 use super::*;
-use crate::{error::*, package::installed::*, package::*};
+use crate::{error::*, package::installed::*, package::*, util};
 
 impl DBConnection {
     /// Queries the database for the package hash matching the supplied name
@@ -67,6 +67,15 @@ impl DBConnection {
     pub fn get_package_dependencies(&mut self, hash: &str) -> Result<Vec<String>, LError> {
         self.new_transaction()?.get_package_dependencies(hash)
     }
+
+    /// Adds the files of the supplied InstalledPackage to the database
+    /// # Arguments
+    /// * `package` - The package to insert the files of
+    pub fn insert_package_files(&mut self, package: &InstalledPackage) -> Result<(), LError> {
+        let transaction = self.new_transaction()?;
+        transaction.insert_package_files(package)?;
+        transaction.commit()
+    }
 }
 
 impl<'a> DBTransaction<'a> {
@@ -78,10 +87,11 @@ impl<'a> DBTransaction<'a> {
     pub fn get_package_hash(&self, name: &str) -> Result<Option<String>, LError> {
         let mut stmt = self
             .transaction
-            .prepare("SELECT hash FROM packages WHERE name = ?")?;
+            .prepare("SELECT hash, hash_algorithm FROM packages WHERE name = ?")?;
         let mut packages_iter = stmt.query_map([name], |row| {
-            let res: String = row.get(0)?;
-            Ok(res)
+            let hash: String = row.get(0)?;
+            let algorithm: String = row.get(1)?;
+            Ok(format!("{}:{}", algorithm, hash))
         })?;
         match packages_iter.next() {
             None => Ok(None),
@@ -150,14 +160,17 @@ impl<'a> DBTransaction<'a> {
         // Prepare the statement and insert the package
         let mut stmt = self
             .transaction
-            .prepare("INSERT INTO packages (name, version, real_version, description, hash) VALUES (?, ?, ?, ?, ?)")?;
+            .prepare("INSERT INTO packages (name, version, real_version, description, hash, hash_algorithm) VALUES (?, ?, ?, ?, ?, ?)")?;
 
+        let hash = package.get_hash();
+        let (algorithm, digest) = util::hash::split_hash(&hash)?;
         stmt.insert([
             package.get_name(),
             package.get_version(),
             package.get_real_version().to_string(),
             package.get_description(),
-            package.get_hash(),
+            digest.to_string(),
+            algorithm.to_string(),
         ])
         .err_prepend(&format!("When inserting package {}", package.get_fq_name()))?;
 
@@ -204,7 +217,7 @@ impl<'a> DBTransaction<'a> {
     /// Retrieves the dependencies of the package matching the supplied hash
     ///
     /// # Arguments
-    /// * `hash` - The hash to use for searching
+    /// * `hash` - The tagged hash to use for searching
     /// # Returns
     /// A vector of strings containing the dependency names
     pub fn get_package_dependencies(&self, hash: &str) -> Result<Vec<String>, LError> {
@@ -212,11 +225,13 @@ impl<'a> DBTransaction<'a> {
             "SELECT p2.name
                         FROM dependencies, packages p1, packages p2
                         WHERE p1.hash = ?
+                            AND p1.hash_algorithm = ?
                             AND p1.id = dependencies.depender
                             AND p2.id = dependencies.dependency;",
         )?;
 
-        let dependencies = stmt.query_map([hash], |row| {
+        let (algorithm, digest) = util::hash::split_hash(hash)?;
+        let dependencies = stmt.query_map([digest, algorithm.as_str()], |row| {
             let res: String = row.get(0)?;
             Ok(res)
         })?;
@@ -230,11 +245,10 @@ impl<'a> DBTransaction<'a> {
         Ok(deps)
     }
 
-    /// Adds the supplied files to the parent owned by the supplied package
+    /// Adds the files of the supplied InstalledPackage to the database,
+    /// replacing the files already recorded for it (e.g. when reinstalling)
     /// # Arguments
-    /// * `pkgid` - The package the files are owned by
-    /// * `parent` - The id of the parent file id (None for root entry)
-    /// * `files` - The files to add
+    /// * `package` - The package to insert the files of
     pub fn insert_package_files(&self, package: &InstalledPackage) -> Result<(), LError> {
         let pkgid = match self.get_package_id(&package.get_name())? {
             Some(id) => id,
@@ -246,6 +260,7 @@ impl<'a> DBTransaction<'a> {
             }
         };
 
+        self.remove_files(pkgid)?;
         self.insert_files(pkgid, None, package.get_files())?;
 
         Ok(())
@@ -1,10 +1,19 @@
+use rusqlite::params;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
 use super::DBConnection;
 
+/// The newest version of the database layout
+const DB_VERSION: usize = 4;
+
 impl DBConnection {
     /// Ensures that the tables needed for operation are available
     ///
     /// Gets run on open automatically
-    pub fn ensure_tables(&self) -> Result<(), rusqlite::Error> {
+    /// # Arguments
+    /// * `root` - The root directory the packages are deployed to, used for upgrading the database
+    pub fn ensure_tables(&self, root: &Path) -> Result<(), rusqlite::Error> {
         let connection = &self.connection;
 
         connection.execute("PRAGMA foreign_keys = ON", ())?;
@@ -16,7 +25,8 @@ impl DBConnection {
                 version         TEXT NOT NULL,
                 real_version    INTEGER NOT NULL,
                 description     TEXT,
-                hash            TEXT
+                hash            TEXT,
+                hash_algorithm  TEXT NOT NULL DEFAULT 'md5'
             )",
             (),
         )?;
@@ -40,7 +50,15 @@ impl DBConnection {
                 parent          INTEGER,
                 package         INTEGER NOT NULL,
                 name            TEXT NOT NULL,
+                type            TEXT,
                 hash            TEXT,
+                hash_algorithm  TEXT,
+                mode            INTEGER,
+                uid             INTEGER,
+                gid             INTEGER,
+                mtime           INTEGER,
+                link_target     TEXT,
+                rdev            INTEGER,
 
                 UNIQUE (parent, package, name),
                 FOREIGN KEY (package) REFERENCES packages(id) ON UPDATE CASCADE ON DELETE CASCADE,
@@ -60,7 +78,18 @@ impl DBConnection {
         match self.reg_get::<usize>("db_version")? {
             None => {
                 info!("Database version not set, assuming create, setting to newest version");
-                self.reg_set("db_version", &1)?;
+                self.reg_set("db_version", &DB_VERSION)?;
+            }
+            Some(version) if version < DB_VERSION => {
+                info!(
+                    "Database version: {} - upgrading to {}",
+                    version, DB_VERSION
+                );
+                // An interrupted upgrade must not leave a half upgraded database behind
+                let transaction = connection.unchecked_transaction()?;
+                self.upgrade_tables(version, root)?;
+                self.reg_set("db_version", &DB_VERSION)?;
+                transaction.commit()?;
             }
             Some(version) => {
                 info!("Database version: {} - no upgrade required", version);
@@ -69,4 +98,209 @@ impl DBConnection {
 
         Ok(())
     }
+
+    /// Upgrades the tables from the supplied version to the newest version,
+    /// `ensure_tables()` runs this in a transaction along with updating the version
+    /// # Arguments
+    /// * `version` - The version the database is currently at
+    /// * `root` - The root directory the packages are deployed to
+    fn upgrade_tables(&self, version: usize, root: &Path) -> Result<(), rusqlite::Error> {
+        let connection = &self.connection;
+
+        // Version 2: The metadata of filesystem entries
+        if version < 2 {
+            debug!("Upgrading database to version 2: fsentries metadata");
+            connection.execute("ALTER TABLE fsentries ADD COLUMN mode INTEGER", ())?;
+            connection.execute("ALTER TABLE fsentries ADD COLUMN uid INTEGER", ())?;
+            connection.execute("ALTER TABLE fsentries ADD COLUMN gid INTEGER", ())?;
+            connection.execute("ALTER TABLE fsentries ADD COLUMN mtime INTEGER", ())?;
+        }
+
+        // Version 3: The type of filesystem entries
+        if version < 3 {
+            debug!("Upgrading database to version 3: fsentries types");
+            connection.execute("ALTER TABLE fsentries ADD COLUMN type TEXT", ())?;
+            connection.execute("ALTER TABLE fsentries ADD COLUMN link_target TEXT", ())?;
+            connection.execute("ALTER TABLE fsentries ADD COLUMN rdev INTEGER", ())?;
+            connection.execute(
+                "UPDATE fsentries SET type = 'directory' WHERE hash IS NULL",
+                (),
+            )?;
+            self.classify_hashed_entries(root)?;
+        }
+
+        // Version 4: The algorithms of the hashes, all hashes so far are MD5
+        if version < 4 {
+            debug!("Upgrading database to version 4: hash algorithms");
+            connection.execute(
+                "ALTER TABLE packages ADD COLUMN hash_algorithm TEXT NOT NULL DEFAULT 'md5'",
+                (),
+            )?;
+            connection.execute("ALTER TABLE fsentries ADD COLUMN hash_algorithm TEXT", ())?;
+            connection.execute(
+                "UPDATE fsentries SET hash_algorithm = 'md5' WHERE hash IS NOT NULL",
+                (),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the type of all entries that have a hash by looking at the deployed entries:
+    /// Before version 3, files and symlinks (hashing their target) were recorded alike.
+    /// The `link_target` is left empty, it only applies to hard links
+    /// # Arguments
+    /// * `root` - The root directory the packages are deployed to
+    fn classify_hashed_entries(&self, root: &Path) -> Result<(), rusqlite::Error> {
+        let connection = &self.connection;
+
+        let mut stmt = connection.prepare("SELECT id, parent, name, hash FROM fsentries")?;
+        let rows = stmt
+            .query_map((), |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<i64>>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?.is_some(),
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let entries: HashMap<i64, (Option<i64>, &str)> = rows
+            .iter()
+            .map(|(id, parent, name, _)| (*id, (*parent, name.as_str())))
+            .collect();
+
+        let mut update = connection.prepare("UPDATE fsentries SET type = ? WHERE id = ?")?;
+        for (id, _, _, hashed) in &rows {
+            if !hashed {
+                continue;
+            }
+
+            // Walk up the parents to get the path relative to the root
+            let mut names: Vec<&str> = Vec::new();
+            let mut cur = Some(*id);
+            while let Some((parent, name)) = cur.and_then(|id| entries.get(&id)) {
+                if names.len() > entries.len() {
+                    break;
+                }
+                names.push(name);
+                cur = *parent;
+            }
+            let path = root.join(names.iter().rev().collect::<PathBuf>());
+
+            let entry_type = match path.is_symlink() {
+                true => "symlink",
+                false => "file",
+            };
+            trace!("Classifying {} as {}", path.to_string_lossy(), entry_type);
+            update.execute(params![entry_type, id])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    /// Creates a database in the version 1 layout at `path`
+    /// # Arguments
+    /// * `path` - The path of the database
+    fn create_v1(path: &Path) {
+        let connection = Connection::open(path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE packages (id INTEGER PRIMARY KEY NOT NULL, name TEXT UNIQUE NOT NULL,
+                    version TEXT NOT NULL, real_version INTEGER NOT NULL, description TEXT, hash TEXT);
+                CREATE TABLE fsentries (id INTEGER PRIMARY KEY, parent INTEGER,
+                    package INTEGER NOT NULL, name TEXT NOT NULL, hash TEXT);
+                CREATE TABLE registry (reg_key TEXT PRIMARY KEY NOT NULL, reg_value TEXT);
+                INSERT INTO registry VALUES ('db_version', '1');
+                INSERT INTO packages VALUES (1, 'glibc', '2.36', 1, '', 'abc');
+                INSERT INTO fsentries VALUES (1, NULL, 1, 'usr', NULL);
+                INSERT INTO fsentries VALUES (2, 1, 1, 'lib', NULL);
+                INSERT INTO fsentries VALUES (3, 2, 1, 'libc.so.6', 'abc');
+                INSERT INTO fsentries VALUES (4, 2, 1, 'libc.so', 'def');",
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn upgrade_classifies_symlinks_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("usr/lib")).unwrap();
+        std::fs::write(root.join("usr/lib/libc.so.6"), "libc").unwrap();
+        std::os::unix::fs::symlink("libc.so.6", root.join("usr/lib/libc.so")).unwrap();
+
+        let path = dir.path().join("installed.db");
+        create_v1(&path);
+
+        let db = DBConnection::open(&path, &root).unwrap();
+        let mut stmt = db
+            .connection
+            .prepare("SELECT type, link_target FROM fsentries ORDER BY id")
+            .unwrap();
+        let rows = stmt
+            .query_map((), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(
+            rows,
+            vec![
+                ("directory".to_string(), None),
+                ("directory".to_string(), None),
+                ("file".to_string(), None),
+                ("symlink".to_string(), None),
+            ]
+        );
+        assert_eq!(db.reg_get::<usize>("db_version").unwrap(), Some(DB_VERSION));
+    }
+
+    #[test]
+    fn failed_upgrade_leaves_the_database_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("installed.db");
+        create_v1(&path);
+        // Makes the last step of the upgrade fail, after the others have run
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute("ALTER TABLE fsentries ADD COLUMN hash_algorithm TEXT", ())
+            .unwrap();
+        drop(connection);
+
+        assert!(DBConnection::open(&path, dir.path()).is_err());
+
+        let connection = Connection::open(&path).unwrap();
+        let columns = connection
+            .prepare("SELECT name FROM pragma_table_info('fsentries')")
+            .unwrap()
+            .query_map((), |row| row.get::<_, String>(0))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(!columns.contains(&"mode".to_string()));
+        let version: String = connection
+            .query_row(
+                "SELECT reg_value FROM registry WHERE reg_key = 'db_version'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(version, "1");
+
+        // Once the cause is gone, the upgrade runs from the start
+        connection
+            .execute("ALTER TABLE fsentries DROP COLUMN hash_algorithm", ())
+            .unwrap();
+        drop(connection);
+        let db = DBConnection::open(&path, dir.path()).unwrap();
+        assert_eq!(db.reg_get::<usize>("db_version").unwrap(), Some(DB_VERSION));
+    }
 }
@@ -1,22 +1,375 @@
-use crate::{pbar, usermsg};
+use crate::config::Config;
+use crate::{pbar, usermsg, userwarn};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use crate::error::*;
-use curl::easy::Easy;
+use curl::easy::{Easy, List};
 use indicatif::HumanBytes;
-use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
+use std::sync::Arc;
 
-/// Downloads the contents of the supplied url
+/// How often failed downloads get retried and how long to wait before the first retry,
+/// the delay doubles with every further retry
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Returns a policy that never retries
+    pub fn none() -> Self {
+        Self {
+            retries: 0,
+            delay: Duration::ZERO,
+        }
+    }
+}
+
+/// The status and headers of the final response of a download
+#[derive(Clone, Debug, Default)]
+pub struct Response {
+    pub code: u32,
+    /// The headers of the response in the order they have been received
+    pub headers: Vec<(String, String)>,
+}
+
+impl Response {
+    /// Returns the value of the first header with the supplied name, ignoring its case
+    /// # Arguments
+    /// * `name` - The name of the header
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl Config {
+    /// Returns the retry policy for downloads
+    ///
+    /// Default: 3 retries, starting with a delay of 1 second
+    pub fn get_retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.download_retries.unwrap_or(3),
+            delay: Duration::from_millis(self.download_retry_delay.unwrap_or(1000)),
+        }
+    }
+}
+
+/// Downloads the contents of the supplied url, `file://` urls and absolute paths are read from the local filesystem
 /// Returns the response code in the Ok() variant, if not 200 this returns Err()
+///
+/// Failed transfers get retried according to `retry`, resuming where they stopped,
+/// so the write function receives every byte exactly once
 pub fn download<'data, F>(
     url: &str,
     message: &str,
     display_bar: bool,
-    mut write_function: F,
+    retry: RetryPolicy,
+    write_function: F,
 ) -> Result<u32, LError>
 where
     F: FnMut(&[u8]) -> bool + Send + 'data,
 {
+    request(url, message, display_bar, retry, &[], write_function).map(|r| r.code)
+}
+
+/// Downloads the contents of the supplied url like `download()`, sending additional request headers
+/// Returns the final response in the Ok() variant, if not 2xx this returns Err()
+///
+/// A `304 Not Modified` response to a conditional request (e.g. using `If-None-Match`) returns Ok()
+/// without any data. Local files are always read and return a response with code `0` and no headers.
+/// # Arguments
+/// * `url` - The url to download
+/// * `message` - The message to display for the download
+/// * `display_bar` - If a progress bar should be rendered
+/// * `retry` - The policy for retrying failed transfers
+/// * `headers` - Additional request headers in the form `Name: value`
+/// * `write_function` - The function receiving the data
+pub fn request<'data, F>(
+    url: &str,
+    message: &str,
+    display_bar: bool,
+    retry: RetryPolicy,
+    headers: &[String],
+    mut write_function: F,
+) -> Result<Response, LError>
+where
+    F: FnMut(&[u8]) -> bool + Send + 'data,
+{
+    let mut received: u64 = 0;
+
+    download_range(
+        url,
+        message,
+        display_bar,
+        0,
+        retry,
+        headers,
+        move |pos, data| match unseen(&mut received, pos, data) {
+            Some(data) => write_function(data),
+            None => true,
+        },
+    )
+}
+
+/// Returns the part of the chunk at `pos` that has not been received yet and advances `received`,
+/// a server ignoring the range of a retry sends the received data again
+/// # Arguments
+/// * `received` - The number of bytes received so far
+/// * `pos` - The position of the chunk within the whole file
+/// * `data` - The data of the chunk
+fn unseen<'a>(received: &mut u64, pos: u64, data: &'a [u8]) -> Option<&'a [u8]> {
+    let skip = received.saturating_sub(pos) as usize;
+    if skip >= data.len() {
+        return None;
+    }
+
+    *received = pos + data.len() as u64;
+    Some(&data[skip..])
+}
+
+/// Downloads the contents of the supplied url starting at byte `offset` using a `Range` request,
+/// `file://` urls and absolute paths are read from the local filesystem
+///
+/// The write function receives the position of each chunk within the whole file: If the server
+/// ignores the range and sends the whole file, the first chunk starts at `0` instead of `offset`.
+/// A `416 Range Not Satisfiable` response means there is nothing left to download and returns `Ok(416)`.
+/// Returns the response code in the Ok() variant, if not 2xx this returns Err()
+///
+/// Transport errors and `408`, `429` and `5xx` responses get retried according to `retry`,
+/// resuming at the last received byte
+/// # Arguments
+/// * `url` - The url to download
+/// * `message` - The message to display for the download
+/// * `display_bar` - If a progress bar should be rendered
+/// * `offset` - The byte to start the download at
+/// * `retry` - The policy for retrying failed transfers
+/// * `write_function` - The function receiving the position and data of each chunk
+pub fn download_from<'data, F>(
+    url: &str,
+    message: &str,
+    display_bar: bool,
+    offset: u64,
+    retry: RetryPolicy,
+    write_function: F,
+) -> Result<u32, LError>
+where
+    F: FnMut(u64, &[u8]) -> bool + Send + 'data,
+{
+    download_range(
+        url,
+        message,
+        display_bar,
+        offset,
+        retry,
+        &[],
+        write_function,
+    )
+    .map(|r| r.code)
+}
+
+/// Downloads the contents of the supplied url starting at byte `offset`, see `download_from()`,
+/// sending additional request headers and returning the final response
+/// # Arguments
+/// * `url` - The url to download
+/// * `message` - The message to display for the download
+/// * `display_bar` - If a progress bar should be rendered
+/// * `offset` - The byte to start the download at
+/// * `retry` - The policy for retrying failed transfers
+/// * `headers` - Additional request headers in the form `Name: value`
+/// * `write_function` - The function receiving the position and data of each chunk
+fn download_range<F>(
+    url: &str,
+    message: &str,
+    display_bar: bool,
+    offset: u64,
+    retry: RetryPolicy,
+    headers: &[String],
+    mut write_function: F,
+) -> Result<Response, LError>
+where
+    F: FnMut(u64, &[u8]) -> bool,
+{
+    if let Some(path) = local_path(url) {
+        return read_local(&path, message, offset, &mut write_function);
+    }
+
+    let mut position = offset;
+    let mut delay = retry.delay;
+    let mut attempt = 0;
+
+    loop {
+        let res = transfer(
+            url,
+            message,
+            display_bar,
+            position,
+            headers,
+            &mut |pos, data| {
+                let ok = write_function(pos, data);
+                if ok {
+                    position = pos + data.len() as u64;
+                }
+                ok
+            },
+        );
+
+        let error = match classify(res, position, !headers.is_empty()) {
+            Attempt::Done(res) => return res,
+            Attempt::Retry(error) => error,
+        };
+
+        if attempt >= retry.retries || !crate::RUNNING.load(Relaxed) {
+            return Err(error);
+        }
+        attempt += 1;
+
+        userwarn!(
+            "{} failed, retrying in {:.1}s ({} of {}): {}",
+            message,
+            delay.as_secs_f32(),
+            attempt,
+            retry.retries,
+            error
+        );
+        std::thread::sleep(delay);
+        delay *= 2;
+    }
+}
+
+/// The outcome of a single transfer
+#[derive(Debug)]
+enum Attempt {
+    /// The transfer is final, successful or not
+    Done(Result<Response, LError>),
+    /// The transfer failed with an error worth retrying
+    Retry(LError),
+}
+
+/// Decides if the result of a transfer is final or should be retried:
+/// Transport errors and `408`, `429` and `5xx` responses get retried
+/// # Arguments
+/// * `res` - The result of the transfer
+/// * `position` - The byte the download has reached
+/// * `conditional` - If the request has been sent with additional (conditional) headers
+fn classify(res: Result<Response, LError>, position: u64, conditional: bool) -> Attempt {
+    match res {
+        Ok(r) if (200..300).contains(&r.code) => Attempt::Done(Ok(r)),
+        Ok(r) if r.code == 416 && position > 0 => Attempt::Done(Ok(r)),
+        Ok(r) if r.code == 304 && conditional => Attempt::Done(Ok(r)),
+        Ok(Response { code, .. }) => {
+            let error = LError::new(LErrorClass::CURLHttpNot2xx, &not_2xx_message(code));
+            match code == 408 || code == 429 || code >= 500 {
+                true => Attempt::Retry(error),
+                false => Attempt::Done(Err(error)),
+            }
+        }
+        Err(e) if e.class == LErrorClass::CURL => Attempt::Retry(e),
+        Err(e) => Attempt::Done(Err(e)),
+    }
+}
+
+/// Returns the message of the error for a response that is not 2xx
+/// # Arguments
+/// * `code` - The response code
+fn not_2xx_message(code: u32) -> String {
+    format!("Expected 2xx, got {}", code)
+}
+
+/// Returns if the supplied download error tells that the requested file does not exist:
+/// A `404 Not Found` or `410 Gone` response or a missing local file
+/// # Arguments
+/// * `error` - The error the download failed with
+pub fn is_not_found(error: &LError) -> bool {
+    match error.class {
+        LErrorClass::IO(std::io::ErrorKind::NotFound) => true,
+        LErrorClass::CURLHttpNot2xx => [404, 410]
+            .iter()
+            .any(|code| error.message.as_deref() == Some(not_2xx_message(*code).as_str())),
+        _ => false,
+    }
+}
+
+/// Returns the path of the local file the supplied url points to
+/// if it is a `file://` url or an absolute path
+/// # Arguments
+/// * `url` - The url to check
+pub fn local_path(url: &str) -> Option<PathBuf> {
+    if url.starts_with('/') {
+        return Some(PathBuf::from(url));
+    }
+
+    url::Url::parse(url)
+        .ok()
+        .filter(|u| u.scheme() == "file")
+        .and_then(|u| u.to_file_path().ok())
+}
+
+/// Reads the local file at `path` starting at byte `offset` and passes its contents to the write function
+/// Returns a response with code `0` and no headers in the Ok() variant, as there is no response
+/// # Arguments
+/// * `path` - The file to read
+/// * `message` - The message to display for the download
+/// * `offset` - The byte to start reading at
+/// * `write_function` - The function receiving the position and data of each chunk
+fn read_local(
+    path: &Path,
+    message: &str,
+    offset: u64,
+    write_function: &mut dyn FnMut(u64, &[u8]) -> bool,
+) -> Result<Response, LError> {
+    let context = format!("When reading {}", path.to_string_lossy());
+
+    usermsg!("{}", message);
+
+    let mut file = File::open(path).err_prepend(&context)?;
+    let mut pos = file.seek(SeekFrom::Start(offset)).err_prepend(&context)?;
+    let mut data = [0u8; 65536];
+
+    loop {
+        if !crate::RUNNING.load(Relaxed) {
+            return Err(LError::new_class(LErrorClass::Abort));
+        }
+
+        let len = file.read(&mut data).err_prepend(&context)?;
+        if len == 0 {
+            return Ok(Response::default());
+        }
+
+        if !write_function(pos, &data[..len]) {
+            return Err(LError::new(
+                LErrorClass::Unknown,
+                &format!("{}: Failed to write data", context),
+            ));
+        }
+        pos += len as u64;
+    }
+}
+
+/// Performs a single transfer of the supplied url starting at byte `offset`
+/// Returns the final response in the Ok() variant, regardless of its code.
+/// Returns `LErrorClass::Abort` if leaf has been told to stop during the transfer
+/// # Arguments
+/// * `url` - The url to download
+/// * `message` - The message to display for the download
+/// * `display_bar` - If a progress bar should be rendered
+/// * `offset` - The byte to start the download at
+/// * `headers` - Additional request headers in the form `Name: value`
+/// * `write_function` - The function receiving the position and data of each chunk
+fn transfer(
+    url: &str,
+    message: &str,
+    display_bar: bool,
+    offset: u64,
+    headers: &[String],
+    write_function: &mut dyn FnMut(u64, &[u8]) -> bool,
+) -> Result<Response, LError> {
     //Store the message as a String
     let progress_message = message.to_owned();
 
@@ -24,7 +377,26 @@ where
     let mut easy = Easy::new();
     easy.url(url).expect("CURL setup: url()");
 
-    let mut error = LError::new_class(LErrorClass::Unknown);
+    if offset > 0 {
+        debug!("Resuming download of {} at byte {}", url, offset);
+        easy.range(&format!("{}-", offset))
+            .expect("CURL setup: range()");
+    }
+
+    if !headers.is_empty() {
+        let mut list = List::new();
+        for header in headers {
+            list.append(header).expect("CURL setup: headers");
+        }
+        easy.http_headers(list).expect("CURL setup: http_headers()");
+    }
+
+    //The status of the last response, redirections send multiple
+    let status = Arc::new(AtomicU32::new(0));
+    let header_status = status.clone();
+    let mut response_headers: Vec<(String, String)> = Vec::new();
+    let header_list = &mut response_headers;
+    let mut position: Option<u64> = None;
 
     //Allow CURL to follow redirections
     easy.follow_location(true)
@@ -64,15 +436,49 @@ where
     match {
         //Create a scoped transfer and perform it
         let mut transfer = easy.transfer();
+        transfer
+            .header_function(move |header| {
+                let header = String::from_utf8_lossy(header);
+
+                //A status line starts a new response, only keep the headers of the last one
+                if header.starts_with("HTTP/") {
+                    if let Some(code) = header
+                        .split_whitespace()
+                        .nth(1)
+                        .and_then(|c| c.parse().ok())
+                    {
+                        header_status.store(code, Relaxed);
+                    }
+                    header_list.clear();
+                } else if let Some((name, value)) = header.split_once(':') {
+                    header_list.push((name.trim().to_owned(), value.trim().to_owned()));
+                }
+                true
+            })
+            .expect("CURL setup: header_function()");
         transfer
             .write_function(move |data| {
                 if !crate::RUNNING.load(Relaxed) {
-                    error.class = LErrorClass::Abort;
                     return Ok(data.len() - 1);
                 }
 
-                match write_function(data) {
-                    true => Ok(data.len()),
+                //The body of an error response is not part of the file
+                let code = status.load(Relaxed);
+                if code >= 300 {
+                    return Ok(data.len());
+                }
+
+                //Only a partial content response continues at the offset
+                let pos = *position.get_or_insert(match code {
+                    206 => offset,
+                    _ => 0,
+                });
+
+                match write_function(pos, data) {
+                    true => {
+                        position = Some(pos + data.len() as u64);
+                        Ok(data.len())
+                    }
                     false => Ok(data.len() - 1),
                 }
             })
@@ -85,24 +491,17 @@ where
         //Perform now
         transfer.perform()
     } {
-        Ok(_) => {
-            let code = easy.response_code().expect("CURL response code");
-            if code < 200 || code >= 300 {
-                Err(LError::new(
-                    LErrorClass::CURLHttpNot2xx,
-                    &format!("Expected 2xx, got {}", code),
-                ))
-            } else {
-                Ok(code)
-            }
-        }
-        Err(e) => {
-            if e.is_write_error() {
-                Err(error)
-            } else {
-                Err(LError::from(e))
-            }
-        }
+        Ok(_) => Ok(Response {
+            code: easy.response_code().expect("CURL response code"),
+            headers: response_headers,
+        }),
+        // The write and progress functions stop the transfer if leaf is told to stop
+        Err(_) if !crate::RUNNING.load(Relaxed) => Err(LError::new_class(LErrorClass::Abort)),
+        Err(e) if e.is_write_error() => Err(LError::new(
+            LErrorClass::Unknown,
+            &format!("{}: Failed to write data", url),
+        )),
+        Err(e) => Err(LError::from(e)),
     }
 }
 
@@ -118,3 +517,191 @@ impl From<curl::Error> for LError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(code: u32) -> Result<Response, LError> {
+        Ok(Response {
+            code,
+            headers: Vec::new(),
+        })
+    }
+
+    fn is_done(attempt: Attempt) -> Option<bool> {
+        match attempt {
+            Attempt::Done(res) => Some(res.is_ok()),
+            Attempt::Retry(_) => None,
+        }
+    }
+
+    #[test]
+    fn successful_responses_are_final() {
+        assert_eq!(is_done(classify(response(200), 0, false)), Some(true));
+        assert_eq!(is_done(classify(response(206), 10, false)), Some(true));
+    }
+
+    #[test]
+    fn missing_files_are_told_apart() {
+        for code in [404, 410] {
+            let Attempt::Done(Err(error)) = classify(response(code), 0, false) else {
+                panic!("{} is not a final error", code);
+            };
+            assert!(is_not_found(&error));
+        }
+        let Attempt::Retry(error) = classify(response(503), 0, false) else {
+            panic!("503 is not retried");
+        };
+        assert!(!is_not_found(&error));
+        assert!(!is_not_found(&LError::new(LErrorClass::CURL, "Timeout")));
+
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let error = download(
+            &missing.to_string_lossy(),
+            "Reading",
+            false,
+            RetryPolicy {
+                retries: 0,
+                delay: Duration::ZERO,
+            },
+            |_| true,
+        )
+        .unwrap_err();
+        assert!(is_not_found(&error));
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        for code in [408, 429, 500, 503] {
+            assert_eq!(is_done(classify(response(code), 0, false)), None);
+        }
+        let error = LError::new(LErrorClass::CURL, "Connection reset");
+        assert_eq!(is_done(classify(Err(error), 0, false)), None);
+    }
+
+    #[test]
+    fn permanent_failures_are_not_retried() {
+        for code in [400, 403, 404] {
+            assert_eq!(is_done(classify(response(code), 0, false)), Some(false));
+        }
+        let error = LError::new_class(LErrorClass::Abort);
+        assert_eq!(is_done(classify(Err(error), 0, false)), Some(false));
+    }
+
+    #[test]
+    fn range_not_satisfiable_only_completes_a_resumed_download() {
+        assert_eq!(is_done(classify(response(416), 10, false)), Some(true));
+        assert_eq!(is_done(classify(response(416), 0, false)), Some(false));
+    }
+
+    #[test]
+    fn not_modified_only_completes_a_conditional_request() {
+        assert_eq!(is_done(classify(response(304), 0, true)), Some(true));
+        assert_eq!(is_done(classify(response(304), 0, false)), Some(false));
+    }
+
+    #[test]
+    fn resent_data_is_skipped() {
+        let mut received = 0;
+        assert_eq!(unseen(&mut received, 0, b"abcd"), Some(&b"abcd"[..]));
+
+        // A server ignoring the range sends the whole file again
+        assert_eq!(unseen(&mut received, 0, b"ab"), None);
+        assert_eq!(unseen(&mut received, 2, b"cdef"), Some(&b"ef"[..]));
+        assert_eq!(received, 6);
+
+        // A partial response continues at the received position
+        assert_eq!(unseen(&mut received, 6, b"gh"), Some(&b"gh"[..]));
+        assert_eq!(received, 8);
+    }
+
+    #[test]
+    fn local_files_are_read_from_the_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"abcdef").unwrap();
+
+        let mut data = Vec::new();
+        download_from(
+            &format!("file://{}", path.to_string_lossy()),
+            "Reading",
+            false,
+            2,
+            RetryPolicy::none(),
+            |pos, chunk| {
+                assert_eq!(pos as usize, 2 + data.len());
+                data.extend_from_slice(chunk);
+                true
+            },
+        )
+        .unwrap();
+
+        assert_eq!(data, b"cdef");
+    }
+
+    /// Serves the supplied raw HTTP responses to one connection each on a local port,
+    /// returns the url to request and a handle returning the number of requests served
+    fn serve(responses: Vec<&'static str>) -> (String, std::thread::JoinHandle<usize>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut served = 0;
+            for response in responses {
+                let (stream, _) = match listener.accept() {
+                    Ok(s) => s,
+                    Err(_) => break,
+                };
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                    line.clear();
+                }
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+                served += 1;
+            }
+            served
+        });
+
+        (url, handle)
+    }
+
+    #[test]
+    fn transient_failures_get_retried() {
+        let (url, handle) = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndata",
+        ]);
+        let retry = RetryPolicy {
+            retries: 1,
+            delay: Duration::ZERO,
+        };
+
+        let mut data = Vec::new();
+        let res = request(&url, "Downloading", false, retry, &[], |chunk| {
+            data.extend_from_slice(chunk);
+            true
+        });
+
+        assert_eq!(res.unwrap().code, 200);
+        assert_eq!(data, b"data");
+        assert_eq!(handle.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn write_failures_are_not_retried() {
+        let (url, handle) = serve(vec!["HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndata"]);
+        let retry = RetryPolicy {
+            retries: 3,
+            delay: Duration::ZERO,
+        };
+
+        let err = request(&url, "Downloading", false, retry, &[], |_| false).unwrap_err();
+
+        assert_eq!(err.class, LErrorClass::Unknown);
+        assert_eq!(handle.join().unwrap(), 1);
+    }
+}
@@ -26,6 +26,17 @@ pub enum LErrorClass {
     UnresolvedDependencies,
     UnexpectedPackageVariant,
     UnexpectedDependenciesVariant,
+    ScriptFailed,
+    TriggerFailed,
+    InvalidManifest,
+    UnsafeArchive,
+    HashMismatch,
+    InvalidSignature,
+    KeyNotFound,
+    Keyring,
+    InvalidUrl,
+    InvalidPackageList,
+    PackageInstalled,
 
     IO(io::ErrorKind),
 }
@@ -104,6 +115,17 @@ impl LError {
             UnresolvedDependencies => "Some dependencies are unresolved",
             UnexpectedPackageVariant => "Unexpected package variant",
             UnexpectedDependenciesVariant => "Unexpected dependencies variant",
+            ScriptFailed => "Package script failed",
+            TriggerFailed => "Trigger failed",
+            InvalidManifest => "Invalid package manifest",
+            UnsafeArchive => "Archive contains an unsafe entry",
+            HashMismatch => "Hash does not match",
+            InvalidSignature => "Signature verification failed",
+            KeyNotFound => "Key could not be found",
+            Keyring => "Keyring error",
+            InvalidUrl => "Invalid url",
+            InvalidPackageList => "Invalid package list",
+            PackageInstalled => "Package is already installed",
             IO(_) => "An IO error occured",
         }
         .to_owned()
@@ -1,10 +1,11 @@
 use crate::{
-    actions::{install, update},
-    config::Config,
+    actions::{install, install_local, update},
+    config::{keyring::Key, Config},
     error::LError,
     mirror::Mirror,
     Leaf,
 };
+use std::path::PathBuf;
 
 impl Leaf {
     /// Constructs a new Leaf handle using the supplied mirrors and a default config
@@ -30,6 +31,63 @@ impl Leaf {
         install(&self.config, packages, &mut self.mirrors, &mut self.pool)
     }
 
+    /// Installs the supplied local `.lfpkg` files, resolving missing dependencies from the mirrors
+    /// # Arguments
+    /// * `files` - The paths to the package files to install
+    pub fn install_local(&mut self, files: &[PathBuf]) -> Result<(), LError> {
+        install_local(&self.config, files, &mut self.mirrors, &mut self.pool)
+    }
+
+    /// Returns all keys in the keyring
+    pub fn get_keys(&self) -> Result<Vec<Key>, LError> {
+        Ok(self.config.get_keyring()?.list().to_vec())
+    }
+
+    /// Imports a new key into the keyring
+    /// # Arguments
+    /// * `name` - The name to store the key under
+    /// * `key` - The hex encoded Ed25519 public key
+    pub fn import_key(&mut self, name: &str, key: &str) -> Result<(), LError> {
+        self.config.get_keyring()?.import(name, key)
+    }
+
+    /// Removes a key from the keyring
+    /// # Arguments
+    /// * `name` - The name of the key
+    pub fn remove_key(&mut self, name: &str) -> Result<(), LError> {
+        self.config.get_keyring()?.remove(name)
+    }
+
+    /// Marks a key of the keyring as trusted for the package list of a mirror
+    /// # Arguments
+    /// * `name` - The name of the key
+    /// * `mirror` - The name of the mirror
+    pub fn trust_key(&mut self, name: &str, mirror: &str) -> Result<(), LError> {
+        self.config.get_keyring()?.trust(name, mirror)
+    }
+
+    /// Revokes the trust of a key of the keyring for the package list of a mirror
+    /// # Arguments
+    /// * `name` - The name of the key
+    /// * `mirror` - The name of the mirror
+    pub fn untrust_key(&mut self, name: &str, mirror: &str) -> Result<(), LError> {
+        self.config.get_keyring()?.untrust(name, mirror)
+    }
+
+    /// Marks a key of the keyring as trusted for verifying packages
+    /// # Arguments
+    /// * `name` - The name of the key
+    pub fn trust_key_for_packages(&mut self, name: &str) -> Result<(), LError> {
+        self.config.get_keyring()?.trust_packages(name)
+    }
+
+    /// Revokes the trust of a key of the keyring for verifying packages
+    /// # Arguments
+    /// * `name` - The name of the key
+    pub fn untrust_key_for_packages(&mut self, name: &str) -> Result<(), LError> {
+        self.config.get_keyring()?.untrust_packages(name)
+    }
+
     /// Clears the internal pool of packages, forcing new resolving of installed,
     /// local and remote packages
     pub fn drop_pool(&mut self) {
@@ -1,4 +1,5 @@
 pub mod actions;
+pub mod capi;
 pub mod config;
 pub mod db;
 pub mod download;
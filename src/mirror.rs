@@ -1,19 +1,55 @@
+pub mod generate;
+pub mod response;
+
 use crate::download;
-use crate::package::{PackageRef, PackageVariant};
+use crate::package::{Package, PackageRef, PackageVariant};
 use crate::{config::Config, usererr, usermsg};
-use serde::{Deserialize, Deserializer};
-use std::path::PathBuf;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 
 use crate::error::*;
 use crate::package::remote::RemotePackage;
+use crate::util::compression::Compression;
+use response::{ListPayload, ListResponse};
+
+/// The maximum size of a decompressed package list in bytes
+const MAX_LIST_SIZE: u64 = 256 * 1024 * 1024;
+
+/// The validators of a stored package list, used for conditional updates
+#[derive(Debug, Deserialize, Serialize)]
+struct ListHeaders {
+    /// The url the package list has been fetched from
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
 
 /// Represents an online mirror leaf can query package lists from to provide packages
 #[derive(Debug, Clone, Deserialize)]
 pub struct Mirror {
     pub name: String,
+    /// The url of the package list, `{base}` gets replaced with the base url
     pub url: String,
 
+    /// The url relative urls and the `{base}` placeholder are resolved against (default: `url`)
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// The template for the urls of the packages, overriding the urls in the package list
+    #[serde(default)]
+    pub package_url: Option<String>,
+    /// The name of the repository the mirror serves (default: `name`),
+    /// package lists describing another repository are refused
+    #[serde(default)]
+    pub repository: Option<String>,
+
+    /// The hex encoded Ed25519 public keys the package list has to be signed with
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// If package lists that are unsigned or can't be verified should be accepted
+    #[serde(default)]
+    pub allow_unsigned: bool,
+
     #[serde(skip)]
     pub packages: Option<Vec<Arc<PackageVariant>>>,
 }
@@ -27,11 +63,16 @@ impl Mirror {
         Mirror {
             name: name.to_owned(),
             url: url.to_owned(),
+            base_url: None,
+            package_url: None,
+            repository: None,
+            keys: Vec::new(),
+            allow_unsigned: false,
             packages: None,
         }
     }
 
-    /// Returns the path the mirror should be stored in
+    /// Returns the path the mirror's package list should be stored in, along with its signature
     /// # Arguments
     /// * `config` - A reference to a leaf config struct for getting the mirrors directory
     pub fn get_path(&self, config: &Config) -> PathBuf {
@@ -40,6 +81,274 @@ impl Mirror {
             .join(self.name.to_owned() + ".json")
     }
 
+    /// Validates the supplied response to a package list request and returns the package list
+    /// to store along with its signature
+    ///
+    /// The response has to deliver a list of valid packages for the repository of this mirror,
+    /// that is not older than the stored one and is signed according to the keys of this mirror
+    /// # Arguments
+    /// * `config` - A reference to a leaf config struct for getting the keyring and stored list
+    /// * `res` - The response of the mirror
+    fn validate(
+        &self,
+        config: &Config,
+        res: &ListResponse,
+    ) -> Result<(String, Option<String>), LError> {
+        let (payload, signature) = res.get_payload()?;
+
+        // The signature covers the bytes of the payload, which get stored as they are
+        self.verify(config, payload.as_bytes(), signature.as_deref())?;
+
+        let list: ListPayload<Vec<RemotePackage>> = ListPayload::parse(&payload).map_err(|e| {
+            LError::new(
+                LErrorClass::InvalidPackageList,
+                &format!("Invalid package list: {}", e),
+            )
+        })?;
+        for package in &list.packages {
+            if let Err(e) = self.get_package_url(package) {
+                warn!("Package list of mirror {}: {}", self.name, e);
+            }
+        }
+
+        // A list for another repository signed by the same keys must not replace this one
+        if let Some(repository) = &list.metadata.repository {
+            if repository != self.get_repository() {
+                return Err(LError::new(
+                    LErrorClass::InvalidPackageList,
+                    &format!(
+                        "The package list describes repository '{}', not '{}'",
+                        repository,
+                        self.get_repository()
+                    ),
+                ));
+            }
+        }
+
+        // Only a stored list that still verifies can tell when it has been generated
+        let stored = match self.get_path(config).exists() {
+            true => self
+                .read_list::<serde::de::IgnoredAny>(config)
+                .map(|stored| stored.metadata.generated)
+                .unwrap_or_else(|e| {
+                    warn!(
+                        "Ignoring stored package list of mirror {}: {}",
+                        self.name, e
+                    );
+                    None
+                }),
+            false => None,
+        };
+        if let (Some(generated), Some(stored)) = (list.metadata.generated, stored) {
+            if generated < stored {
+                return Err(LError::new(
+                    LErrorClass::InvalidPackageList,
+                    &format!(
+                        "The package list has been generated at {}, before the stored one ({})",
+                        generated, stored
+                    ),
+                ));
+            }
+        }
+
+        Ok((payload, signature))
+    }
+
+    /// Returns the path the validators (`ETag` and `Last-Modified`) of the mirror's package list
+    /// should be stored in
+    /// # Arguments
+    /// * `config` - A reference to a leaf config struct for getting the mirrors directory
+    pub fn get_headers_path(&self, config: &Config) -> PathBuf {
+        config
+            .get_mirrors_dir()
+            .join(self.name.to_owned() + ".json.headers")
+    }
+
+    /// Returns the headers for a conditional request of the package list, so the mirror
+    /// only sends it if it has changed since the last update
+    ///
+    /// There are none if there is no stored package list, it does not verify or load
+    /// (e.g. as the keys of the mirror have changed) or it has been fetched from another url
+    /// # Arguments
+    /// * `config` - A reference to a leaf config struct for getting the mirrors directory
+    /// * `url` - The url the package list gets fetched from
+    fn get_conditional_headers(&self, config: &Config, url: &str) -> Vec<String> {
+        let mut headers = Vec::new();
+        let path = self.get_headers_path(config);
+        if !self.get_path(config).exists() || !path.exists() {
+            return headers;
+        }
+
+        // A 304 response would keep a stored list that can't be used
+        if let Err(e) = self.read_packages(config) {
+            debug!(
+                "Updating mirror {} unconditionally, the stored list is unusable: {}",
+                self.name, e
+            );
+            return headers;
+        }
+
+        let stored: ListHeaders = match std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| serde_json::from_str(&data).map_err(|e| e.to_string()))
+        {
+            Ok(stored) => stored,
+            Err(e) => {
+                warn!("Ignoring headers of mirror {}: {}", self.name, e);
+                return headers;
+            }
+        };
+
+        if stored.url == url {
+            if let Some(etag) = stored.etag {
+                headers.push(format!("If-None-Match: {}", etag));
+            }
+            if let Some(last_modified) = stored.last_modified {
+                headers.push(format!("If-Modified-Since: {}", last_modified));
+            }
+        }
+
+        headers
+    }
+
+    /// Stores the validators of the supplied package list response for conditional updates
+    /// # Arguments
+    /// * `config` - A reference to a leaf config struct for getting the mirrors directory
+    /// * `url` - The url the package list has been fetched from
+    /// * `response` - The response delivering the package list
+    fn save_list_headers(
+        &self,
+        config: &Config,
+        url: &str,
+        response: &download::Response,
+    ) -> Result<(), LError> {
+        let path = self.get_headers_path(config);
+        let headers = ListHeaders {
+            url: url.to_owned(),
+            etag: response.get_header("ETag").map(String::from),
+            last_modified: response.get_header("Last-Modified").map(String::from),
+        };
+
+        if headers.etag.is_none() && headers.last_modified.is_none() {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+
+        let data = serde_json::to_string(&headers).map_err(|e| {
+            LError::new(
+                LErrorClass::JSON,
+                &format!("When storing headers of mirror {}: {}", self.name, e),
+            )
+        })?;
+        write_atomic(&path, data.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Returns the name of the repository this mirror serves: `repository` if it is set, else its name
+    pub fn get_repository(&self) -> &str {
+        self.repository.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Returns the url relative urls of this mirror are resolved against:
+    /// `base_url` if it is set, else the url of the package list
+    pub fn get_base_url(&self) -> &str {
+        self.base_url.as_deref().unwrap_or(&self.url)
+    }
+
+    /// Returns the url to fetch the package list from, with the `{base}` placeholder
+    /// replaced and resolved against `base_url` if it is relative
+    pub fn get_list_url(&self) -> Result<String, LError> {
+        let url = expand_template(&self.url, &[("base", self.get_base_url())]);
+
+        match &self.base_url {
+            Some(base) => resolve_url(base, &url),
+            None => Ok(url),
+        }
+    }
+
+    /// Returns the url to fetch the supplied package from
+    ///
+    /// If the mirror has a `package_url` template, the url gets built from it. It can use the
+    /// placeholders `{base}`, `{name}`, `{version}`, `{real_version}`, `{full_name}` and `{hash}`
+    /// (the hex digest). Otherwise the url of the package list gets used. Relative urls are
+    /// resolved against the base url.
+    /// # Arguments
+    /// * `package` - The package to get the url for
+    pub fn get_package_url(&self, package: &RemotePackage) -> Result<String, LError> {
+        let url = match &self.package_url {
+            Some(template) => {
+                let hash = package.get_hash();
+                let (_, digest) = crate::util::hash::split_hash(&hash)?;
+                expand_template(
+                    template,
+                    &[
+                        ("base", self.get_base_url()),
+                        ("name", &package.get_name()),
+                        ("version", &package.get_version()),
+                        ("real_version", &package.get_real_version().to_string()),
+                        ("full_name", &package.get_full_name()),
+                        ("hash", digest),
+                    ],
+                )
+            }
+            None if package.get_url().is_empty() => {
+                return Err(LError::new(
+                    LErrorClass::InvalidUrl,
+                    &format!("Package {} has no url", package.get_fq_name()),
+                ))
+            }
+            None => package.get_url().to_owned(),
+        };
+
+        resolve_url(&self.get_resolve_base()?, &url)
+            .err_prepend(&format!("When resolving url of {}", package.get_fq_name()))
+    }
+
+    /// Returns the url to fetch the detached signature of the supplied package from,
+    /// once its url has been resolved using `get_package_url()`
+    ///
+    /// This is the `signature` of the package in the list, resolved against the base url
+    /// like package urls, or the package url with `.sig` appended to its path
+    /// # Arguments
+    /// * `package` - The package with its url resolved
+    pub fn get_signature_url(&self, package: &RemotePackage) -> Result<String, LError> {
+        resolve_url(&self.get_resolve_base()?, &package.get_signature_url()).err_prepend(&format!(
+            "When resolving signature url of {}",
+            package.get_fq_name()
+        ))
+    }
+
+    /// Returns the url relative package and signature urls get resolved against:
+    /// `base_url` if it is set, else the url of the package list
+    fn get_resolve_base(&self) -> Result<String, LError> {
+        match &self.base_url {
+            Some(base) => Ok(base.clone()),
+            None => self.get_list_url(),
+        }
+    }
+
+    /// Checks the supplied package list against the keys of this mirror
+    /// and the keys of the keyring trusted for it
+    /// # Arguments
+    /// * `config` - The config to get the keyring from
+    /// * `data` - The package list
+    /// * `signature` - The hex encoded signature of the package list, if any
+    fn verify(&self, config: &Config, data: &[u8], signature: Option<&str>) -> Result<(), LError> {
+        let mut keys = self.keys.clone();
+        keys.append(&mut config.get_keyring()?.get_mirror_keys(&self.name));
+
+        crate::util::signature::check(
+            data,
+            signature,
+            &keys,
+            self.allow_unsigned,
+            &format!("package list of mirror {}", self.name),
+        )
+    }
+
     /// Queries the mirrors url for fetching the latest package list
     /// # Arguments
     /// * `config` - A reference to a leaf config struct for getting the save path and behaviour information
@@ -47,17 +356,20 @@ impl Mirror {
         crate::util::ensure_dirs(config)?;
 
         let mut buf: Vec<u8> = Vec::new();
+        let url = self.get_list_url()?;
 
-        match download::download(
-            &self.url,
+        let response = match download::request(
+            &url,
             format!("Updating mirror {}...", self.name).as_str(),
             config.render_bar,
+            config.get_retry_policy(),
+            &self.get_conditional_headers(config, &url),
             |data| {
                 buf.extend_from_slice(data);
                 true
             },
         ) {
-            Ok(_) => (),
+            Ok(response) => response,
             Err(e) => {
                 usererr!(
                     "Failed to update mirror {}: {}",
@@ -66,20 +378,63 @@ impl Mirror {
                 );
                 return Err(e);
             }
+        };
+
+        if response.code == 304 {
+            usermsg!("Mirror {} is up to date", &self.name);
+            return Ok(());
         }
 
-        let reader = std::io::Cursor::new(buf);
-        let res: serde_json::Value = match serde_json::from_reader(reader) {
+        // Package lists may be compressed, which the content type or the data itself tells
+        let compression = response
+            .get_header("Content-Type")
+            .and_then(Compression::from_content_type)
+            .unwrap_or_else(|| Compression::detect(&buf));
+        debug!(
+            "Package list of mirror {} uses {:?}",
+            self.name, compression
+        );
+        let buf = compression
+            .decompress(buf, MAX_LIST_SIZE)
+            .err_prepend(&format!(
+                "When decompressing package list of mirror {}",
+                self.name
+            ))?;
+
+        // Validate everything before touching the stored list
+        let validated = ListResponse::parse(&buf)
+            .and_then(|res| self.validate(config, &res))
+            .err_prepend(&format!("When updating mirror {}", self.name));
+        let (payload, signature) = match validated {
             Ok(v) => v,
             Err(e) => {
-                return Err(LError::new(
-                    LErrorClass::JSON,
-                    format!("When updating mirror {}: {}", self.name, e).as_str(),
-                ))
+                usererr!("Failed to update mirror {}: {}", &self.name, e);
+                return Err(e);
             }
         };
 
-        std::fs::write(self.get_path(config), res["payload"].to_string())?;
+        // The payload is stored along with its signature, so both get replaced at once
+        let stored = ListResponse {
+            status: None,
+            payload: Some(serde_json::Value::String(payload)),
+            signature,
+        };
+        let data = serde_json::to_string(&stored).map_err(|e| {
+            LError::new(
+                LErrorClass::JSON,
+                &format!("When storing package list of mirror {}: {}", self.name, e),
+            )
+        })?;
+        write_atomic(&self.get_path(config), data.as_bytes())?;
+        self.save_list_headers(config, &url, &response)?;
+
+        // Lists stored before kept their signature in a separate file
+        let legacy_signature = config
+            .get_mirrors_dir()
+            .join(self.name.to_owned() + ".json.sig");
+        if legacy_signature.exists() {
+            std::fs::remove_file(legacy_signature)?;
+        }
 
         usermsg!("Updated mirror {}", &self.name);
 
@@ -96,8 +451,20 @@ impl Mirror {
             self.get_path(config).to_str().unwrap_or("")
         );
 
-        let data = std::fs::read_to_string(self.get_path(config))?;
+        self.packages = Some(self.read_packages(config)?);
+
+        for package in &self.packages {
+            trace!("Mirror {} has {:?}", self.name, package);
+        }
+
+        Ok(())
+    }
 
+    /// Reads and verifies the stored package list and returns its packages,
+    /// with their urls resolved
+    /// # Arguments
+    /// * `config` - A reference to a leaf config struct for getting the mirrors directory
+    fn read_packages(&self, config: &Config) -> Result<Vec<Arc<PackageVariant>>, LError> {
         #[derive(Deserialize)]
         #[serde(transparent)]
         struct DE {
@@ -105,22 +472,45 @@ impl Mirror {
             data: Vec<Arc<PackageVariant>>,
         }
 
-        let buf: DE = match serde_json::from_str(&data) {
-            Ok(v) => v,
-            Err(e) => {
-                return Err(LError::new(
-                    LErrorClass::JSON,
-                    format!("When loading mirror {}: {}", self.name, e).as_str(),
-                ))
+        let buf: ListPayload<DE> = self.read_list(config)?;
+        // A package without a resolvable url can't be fetched, but does not spoil the others
+        let mut packages = Vec::with_capacity(buf.packages.data.len());
+        for mut package in buf.packages.data {
+            if let Some(PackageVariant::Remote(remote)) = Arc::get_mut(&mut package) {
+                let resolved = self.get_package_url(remote).and_then(|url| {
+                    remote.set_url(&url);
+                    self.get_signature_url(remote)
+                });
+                match resolved {
+                    Ok(signature_url) => remote.set_signature_url(&signature_url),
+                    Err(e) => {
+                        warn!("Skipping package of mirror {}: {}", self.name, e);
+                        continue;
+                    }
+                }
             }
-        };
-        self.packages = Some(buf.data);
-
-        for package in &self.packages {
-            trace!("Mirror {} has {:?}", self.name, package);
+            packages.push(package);
         }
 
-        Ok(())
+        Ok(packages)
+    }
+
+    /// Reads, verifies and parses the stored package list
+    /// # Arguments
+    /// * `config` - A reference to a leaf config struct for getting the mirrors directory
+    fn read_list<T: DeserializeOwned>(&self, config: &Config) -> Result<ListPayload<T>, LError> {
+        let data = std::fs::read(self.get_path(config))?;
+        let (payload, signature) = ListResponse::parse(&data)
+            .and_then(|stored| stored.get_payload())
+            .err_prepend(&format!("When loading mirror {}", self.name))?;
+        self.verify(config, payload.as_bytes(), signature.as_deref())?;
+
+        ListPayload::parse(&payload).map_err(|e| {
+            LError::new(
+                LErrorClass::JSON,
+                format!("When loading mirror {}: {}", self.name, e).as_str(),
+            )
+        })
     }
 
     /// Searches this mirror for a package with the supplied name
@@ -161,17 +551,39 @@ impl Mirror {
 }
 
 /// Searches the provided mirrors for a package with the supplied name.
+///
+/// The urls of the same package (with the same hash) on the following mirrors
+/// are added as fallbacks for fetching it
 /// # Arguments
 /// * `name` - The package name to search for
 /// * `mirrors` - The mirrors to search in
 /// # Returns
 /// A clone of the package
 pub fn resolve_package(name: &str, mirrors: &[Mirror]) -> Result<PackageRef, LError> {
-    for mirror in mirrors {
+    for (i, mirror) in mirrors.iter().enumerate() {
         match mirror.find_package(name) {
             Ok(p) => {
                 debug!("Mirror {} has package {}", mirror.name, name);
-                return Ok(Arc::new(RwLock::new(p.as_ref().clone())));
+                let mut package = p.as_ref().clone();
+
+                if let PackageVariant::Remote(remote) = &mut package {
+                    for other in &mirrors[i + 1..] {
+                        let found = other
+                            .packages
+                            .as_deref()
+                            .and_then(|p| crate::util::find_package_hash(&remote.get_hash(), p));
+
+                        if let Some(PackageVariant::Remote(fallback)) = found.as_deref() {
+                            debug!("Mirror {} provides {} as well", other.name, name);
+                            remote.add_fallback_url(
+                                fallback.get_url(),
+                                &fallback.get_signature_url(),
+                            );
+                        }
+                    }
+                }
+
+                return Ok(Arc::new(RwLock::new(package)));
             }
             Err(e) => {
                 if e.class == LErrorClass::PackageNotFound {
@@ -186,8 +598,379 @@ pub fn resolve_package(name: &str, mirrors: &[Mirror]) -> Result<PackageRef, LEr
     Err(LError::new(LErrorClass::PackageNotFound, name))
 }
 
+/// Writes the supplied data to a temporary file next to `path` and renames it into place,
+/// so a stored file is never left half written
+/// # Arguments
+/// * `path` - The path to write to
+/// * `data` - The data to write
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), LError> {
+    let temp = crate::util::fs::TempFile::new(path);
+    std::fs::write(temp.path(), data)?;
+    temp.persist(path)?;
+    Ok(())
+}
+
+/// Replaces the `{key}` placeholders in the supplied template with their values,
+/// unknown placeholders are left untouched
+/// # Arguments
+/// * `template` - The template to expand
+/// * `values` - The placeholders and their values
+fn expand_template(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_owned(), |url, (key, value)| {
+            let value = match *key {
+                "base" => value.trim_end_matches('/'),
+                _ => value,
+            };
+            url.replace(&format!("{{{}}}", key), value)
+        })
+}
+
+/// Resolves the supplied url against a base url, absolute urls are returned as they are
+///
+/// An absolute path as the base is treated as a `file://` url
+/// # Arguments
+/// * `base` - The url to resolve relative urls against
+/// * `url` - The absolute or relative url
+fn resolve_url(base: &str, url: &str) -> Result<String, LError> {
+    let invalid = |reason: String| {
+        LError::new(
+            LErrorClass::InvalidUrl,
+            &format!("Can't resolve '{}' against '{}': {}", url, base, reason),
+        )
+    };
+
+    let base = match base.starts_with('/') {
+        true => url::Url::from_file_path(base)
+            .map_err(|_| invalid("Not an absolute path".to_string()))?,
+        false => url::Url::parse(base).map_err(|e| invalid(e.to_string()))?,
+    };
+
+    Ok(base
+        .join(url)
+        .map_err(|e| invalid(e.to_string()))?
+        .to_string())
+}
+
 impl PartialEq for Mirror {
     fn eq(&self, other: &Self) -> bool {
         self.name == other.name
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::signature;
+
+    const SECRET: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+    /// Returns a config working in the supplied directory
+    fn config(dir: &Path) -> Config {
+        Config {
+            root: Some(dir.to_path_buf()),
+            render_bar: false,
+            ..Default::default()
+        }
+    }
+
+    /// Writes the supplied response to `dir/list.json` and returns a mirror serving it,
+    /// trusting the key of `SECRET`
+    fn serve(dir: &Path, res: &ListResponse) -> Mirror {
+        let path = dir.join("list.json");
+        std::fs::write(&path, serde_json::to_string(res).unwrap()).unwrap();
+
+        let mut mirror = Mirror::new("test", &path.to_string_lossy());
+        mirror.keys = vec![signature::public_key(SECRET).unwrap()];
+        mirror
+    }
+
+    /// Returns a response delivering `payload` as a string, signed by `SECRET`
+    fn signed(payload: &str) -> ListResponse {
+        ListResponse {
+            payload: Some(serde_json::Value::String(payload.to_owned())),
+            signature: Some(signature::sign(payload.as_bytes(), SECRET).unwrap()),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the payload and signature the mirror has stored
+    fn stored(mirror: &Mirror, config: &Config) -> (String, Option<String>) {
+        let data = std::fs::read(mirror.get_path(config)).unwrap();
+        ListResponse::parse(&data).unwrap().get_payload().unwrap()
+    }
+
+    #[test]
+    fn signed_payload_bytes_are_stored_as_they_are() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        // Formatting that a re-serialization would not reproduce
+        let payload = "[ ]";
+        let mut mirror = serve(dir.path(), &signed(payload));
+
+        mirror.update(&config).unwrap();
+
+        assert_eq!(stored(&mirror, &config).0, payload);
+        mirror.load(&config).unwrap();
+        assert_eq!(mirror.packages.map(|p| p.len()), Some(0));
+    }
+
+    #[test]
+    fn tampered_payload_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let mut res = signed("[]");
+        res.payload = Some(serde_json::Value::String("[ ]".to_owned()));
+        let mirror = serve(dir.path(), &res);
+
+        let err = mirror.update(&config).unwrap_err();
+
+        assert_eq!(err.class, LErrorClass::InvalidSignature);
+        assert!(!mirror.get_path(&config).exists());
+    }
+
+    #[test]
+    fn list_payload_is_treated_as_unsigned() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let mut res = signed("[]");
+        res.payload = Some(serde_json::json!([]));
+        let mut mirror = serve(dir.path(), &res);
+
+        let err = mirror.update(&config).unwrap_err();
+        assert_eq!(err.class, LErrorClass::InvalidSignature);
+
+        mirror.allow_unsigned = true;
+        mirror.update(&config).unwrap();
+        assert_eq!(stored(&mirror, &config).1, None);
+    }
+
+    #[test]
+    fn signature_is_stored_along_with_the_payload() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let res = signed("[]");
+        let mut mirror = serve(dir.path(), &res);
+        // Left behind by a version storing the signature separately
+        std::fs::create_dir_all(config.get_mirrors_dir()).unwrap();
+        let legacy = config.get_mirrors_dir().join("test.json.sig");
+        std::fs::write(&legacy, "00").unwrap();
+
+        mirror.update(&config).unwrap();
+
+        assert_eq!(stored(&mirror, &config), ("[]".to_owned(), res.signature));
+        assert!(!legacy.exists());
+        mirror.load(&config).unwrap();
+    }
+
+    #[test]
+    fn missing_payload_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let mut mirror = serve(dir.path(), &ListResponse::default());
+        mirror.allow_unsigned = true;
+
+        let err = mirror.update(&config).unwrap_err();
+
+        assert_eq!(err.class, LErrorClass::InvalidPackageList);
+        assert!(!mirror.get_path(&config).exists());
+    }
+
+    #[test]
+    fn non_ok_status_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let mut res = signed("[]");
+        res.status = Some("maintenance".to_owned());
+        let mirror = serve(dir.path(), &res);
+
+        let err = mirror.update(&config).unwrap_err();
+
+        assert_eq!(err.class, LErrorClass::InvalidPackageList);
+        assert!(!mirror.get_path(&config).exists());
+    }
+
+    /// Returns a signed payload holding no packages, generated at `generated`
+    fn generated_at(generated: u64) -> String {
+        serde_json::json!({"metadata": {"generated": generated}, "packages": []}).to_string()
+    }
+
+    #[test]
+    fn older_list_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let mirror = serve(dir.path(), &signed(&generated_at(200)));
+        mirror.update(&config).unwrap();
+
+        let mirror = serve(dir.path(), &signed(&generated_at(100)));
+        let err = mirror.update(&config).unwrap_err();
+
+        assert_eq!(err.class, LErrorClass::InvalidPackageList);
+        assert_eq!(stored(&mirror, &config).0, generated_at(200));
+
+        // The same or a newer list is fine
+        for generated in [200, 300] {
+            let mirror = serve(dir.path(), &signed(&generated_at(generated)));
+            mirror.update(&config).unwrap();
+        }
+    }
+
+    #[test]
+    fn lists_for_other_repositories_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let payload = |repository: &str| {
+            serde_json::json!({"metadata": {"repository": repository}, "packages": []}).to_string()
+        };
+
+        let mut mirror = serve(dir.path(), &signed(&payload("core")));
+        let err = mirror.update(&config).unwrap_err();
+        assert_eq!(err.class, LErrorClass::InvalidPackageList);
+        assert!(!mirror.get_path(&config).exists());
+
+        mirror.repository = Some("core".to_owned());
+        mirror.update(&config).unwrap();
+
+        // The repository defaults to the name of the mirror
+        let mirror = serve(dir.path(), &signed(&payload("test")));
+        mirror.update(&config).unwrap();
+    }
+
+    #[test]
+    fn generation_time_is_covered_by_the_signature() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let mut res = signed(&generated_at(100));
+        res.payload = Some(serde_json::Value::String(generated_at(300)));
+        let mirror = serve(dir.path(), &res);
+
+        let err = mirror.update(&config).unwrap_err();
+
+        assert_eq!(err.class, LErrorClass::InvalidSignature);
+    }
+
+    #[test]
+    fn expand_template_replaces_known_placeholders() {
+        let url = expand_template(
+            "{base}/packages/{name}/{full_name}.lfpkg?{unknown}",
+            &[
+                ("base", "https://mirror.example.org/acacia/"),
+                ("name", "glibc"),
+                ("full_name", "glibc-2.36"),
+            ],
+        );
+
+        assert_eq!(
+            url,
+            "https://mirror.example.org/acacia/packages/glibc/glibc-2.36.lfpkg?{unknown}"
+        );
+    }
+
+    #[test]
+    fn resolve_url_handles_relative_and_absolute_urls() {
+        let base = "https://mirror.example.org/acacia/";
+
+        assert_eq!(
+            resolve_url(base, "packages/glibc-2.36.lfpkg").unwrap(),
+            "https://mirror.example.org/acacia/packages/glibc-2.36.lfpkg"
+        );
+        assert_eq!(
+            resolve_url(base, "/other/glibc-2.36.lfpkg").unwrap(),
+            "https://mirror.example.org/other/glibc-2.36.lfpkg"
+        );
+        assert_eq!(
+            resolve_url(base, "https://cdn.example.org/glibc-2.36.lfpkg").unwrap(),
+            "https://cdn.example.org/glibc-2.36.lfpkg"
+        );
+        // Without a trailing slash, the last component is a file
+        assert_eq!(
+            resolve_url("https://mirror.example.org/acacia/list.json", "glibc.lfpkg").unwrap(),
+            "https://mirror.example.org/acacia/glibc.lfpkg"
+        );
+    }
+
+    #[test]
+    fn resolve_url_handles_local_paths() {
+        assert_eq!(
+            resolve_url("/mnt/usb/acacia/list.json", "packages/glibc.lfpkg").unwrap(),
+            "file:///mnt/usb/acacia/packages/glibc.lfpkg"
+        );
+        assert_eq!(
+            resolve_url("file:///mnt/usb/acacia/", "glibc.lfpkg").unwrap(),
+            "file:///mnt/usb/acacia/glibc.lfpkg"
+        );
+        assert!(resolve_url("not a url", "glibc.lfpkg").is_err());
+    }
+
+    /// Returns the mirror entry of `glibc-2.36` with the supplied url
+    fn entry(url: &str) -> serde_json::Value {
+        serde_json::json!({
+            "name": "glibc",
+            "version": "2.36",
+            "real_version": 1,
+            "description": "",
+            "dependencies": [],
+            "hash": "blake3:0123abcd",
+            "url": url,
+        })
+    }
+
+    #[test]
+    fn package_url_template_uses_the_hex_digest() {
+        let mut mirror = Mirror::new("test", "{base}/list.json");
+        mirror.base_url = Some("https://mirror.example.org/acacia/".to_owned());
+        mirror.package_url = Some("{base}/by-hash/{hash}/{full_name}.lfpkg".to_owned());
+
+        assert_eq!(
+            mirror.get_list_url().unwrap(),
+            "https://mirror.example.org/acacia/list.json"
+        );
+        assert_eq!(
+            mirror
+                .get_package_url(&serde_json::from_value(entry("")).unwrap())
+                .unwrap(),
+            "https://mirror.example.org/acacia/by-hash/0123abcd/glibc-2.36.lfpkg"
+        );
+    }
+
+    #[test]
+    fn packages_without_url_are_skipped_when_loading() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let payload = serde_json::json!([entry(""), entry("glibc.lfpkg")]).to_string();
+        let mut mirror = serve(dir.path(), &signed(&payload));
+        mirror.update(&config).unwrap();
+
+        mirror.load(&config).unwrap();
+
+        let packages = mirror.packages.unwrap();
+        assert_eq!(packages.len(), 1);
+    }
+
+    #[test]
+    fn conditional_headers_require_a_usable_stored_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let mut mirror = serve(dir.path(), &signed("[]"));
+        let url = mirror.get_list_url().unwrap();
+        mirror.update(&config).unwrap();
+        let response = download::Response {
+            code: 200,
+            headers: vec![("ETag".to_owned(), "\"v1\"".to_owned())],
+        };
+        mirror.save_list_headers(&config, &url, &response).unwrap();
+
+        assert_eq!(
+            mirror.get_conditional_headers(&config, &url),
+            vec!["If-None-Match: \"v1\"".to_owned()]
+        );
+        assert!(mirror
+            .get_conditional_headers(&config, "/other/list.json")
+            .is_empty());
+
+        // The stored list no longer verifies using the keys of the mirror
+        mirror.keys = vec![signature::public_key(&"11".repeat(32)).unwrap()];
+        assert!(mirror.get_conditional_headers(&config, &url).is_empty());
+    }
+}
@@ -0,0 +1,294 @@
+//! This module provides generating the package list of a mirror from a directory of packages
+use percent_encoding::{percent_encode, AsciiSet, CONTROLS};
+use serde::Serialize;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::response::{ListMetadata, ListPayload, ListResponse, STATUS_OK};
+use crate::error::*;
+use crate::package::manifest::PackageManifest;
+use crate::util;
+
+/// The characters that have to be percent-encoded in a path segment of a url
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// An entry of the package list, as `Mirror::load()` expects it
+#[derive(Clone, Debug, Serialize)]
+pub struct PackageListEntry {
+    pub name: String,
+    pub version: String,
+    pub real_version: u64,
+    pub description: String,
+    pub dependencies: Vec<String>,
+    pub hash: String,
+    /// The path of the package relative to the scanned directory, as a percent-encoded url
+    pub url: String,
+    /// The url of the detached signature next to the package, if there is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// Scans `dir` recursively for `.lfpkg` files and creates a package list entry for each of them
+///
+/// The entries are sorted by name and newest version first, their urls are relative to `dir`
+/// with each path segment percent-encoded. Detached signatures next to the packages get listed as well.
+/// # Arguments
+/// * `dir` - The directory containing the packages
+pub fn scan_packages(dir: &Path) -> Result<Vec<PackageListEntry>, LError> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    find_packages(dir, &mut files)
+        .err_prepend(&format!("When scanning {}", dir.to_string_lossy()))?;
+
+    let mut entries = Vec::new();
+    for file in files {
+        let context = format!("When adding package {}", file.to_string_lossy());
+        debug!("Adding package {}", file.to_string_lossy());
+
+        let manifest = PackageManifest::from_archive(&file).err_prepend(&context)?;
+        let hash = util::hash::hash_file(&file).err_prepend(&context)?;
+        let url = relative_url(file.strip_prefix(dir).unwrap_or(&file));
+        let sig_path = util::signature::signature_path(&file);
+        let signature = sig_path
+            .exists()
+            .then(|| relative_url(sig_path.strip_prefix(dir).unwrap_or(&sig_path)));
+
+        entries.push(PackageListEntry {
+            name: manifest.name,
+            version: manifest.version,
+            real_version: manifest.real_version,
+            description: manifest.description,
+            dependencies: manifest.dependencies,
+            hash,
+            url,
+            signature,
+        });
+    }
+
+    entries.sort_by(|a, b| {
+        a.name
+            .cmp(&b.name)
+            .then(b.real_version.cmp(&a.real_version))
+    });
+
+    Ok(entries)
+}
+
+/// Writes the supplied entries as a package list envelope to `dest`, stamped with the
+/// repository name and the current time and signed if a secret key is supplied
+/// # Arguments
+/// * `entries` - The entries of the package list
+/// * `dest` - The path to write the package list to
+/// * `repository` - The name of the repository the list describes, see `Mirror::repository`
+/// * `secret_key` - The hex encoded Ed25519 secret key to sign the list with, if any
+pub fn write_package_list(
+    entries: &[PackageListEntry],
+    dest: &Path,
+    repository: &str,
+    secret_key: Option<&str>,
+) -> Result<(), LError> {
+    let context = format!("When writing package list {}", dest.to_string_lossy());
+
+    let generated = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    // The metadata is part of the payload, so the signature covers it as well
+    let payload = ListPayload {
+        metadata: ListMetadata {
+            repository: Some(repository.to_owned()),
+            generated: Some(generated),
+        },
+        packages: entries,
+    };
+    let payload = serde_json::to_string(&payload).map_err(|e| {
+        LError::new(
+            LErrorClass::JSON,
+            &format!("{}: When serializing package list: {}", context, e),
+        )
+    })?;
+
+    // The payload is embedded as a string, so the signed bytes reach `Mirror::update()` untouched
+    let signature = match secret_key {
+        Some(secret_key) => {
+            Some(util::signature::sign(payload.as_bytes(), secret_key).err_prepend(&context)?)
+        }
+        None => None,
+    };
+
+    let envelope = ListResponse {
+        status: Some(STATUS_OK.to_owned()),
+        payload: Some(serde_json::Value::String(payload)),
+        signature,
+    };
+    let data = serde_json::to_string(&envelope).map_err(|e| {
+        LError::new(
+            LErrorClass::JSON,
+            &format!("{}: When serializing package list: {}", context, e),
+        )
+    })?;
+
+    // Write to a temporary file first so the mirror never serves a partial list
+    let temp = util::fs::TempFile::new(dest);
+    std::fs::write(temp.path(), data).err_prepend(&context)?;
+    temp.persist(dest).err_prepend(&context)?;
+
+    Ok(())
+}
+
+/// Generates the package list for the packages in `dir` and writes it to `dest`,
+/// returning the number of packages in it
+/// # Arguments
+/// * `dir` - The directory containing the packages
+/// * `dest` - The path to write the package list to
+/// * `repository` - The name of the repository the list describes, see `Mirror::repository`
+/// * `secret_key` - The hex encoded Ed25519 secret key to sign the list with, if any
+pub fn generate_package_list(
+    dir: &Path,
+    dest: &Path,
+    repository: &str,
+    secret_key: Option<&str>,
+) -> Result<usize, LError> {
+    let entries = scan_packages(dir)?;
+    write_package_list(&entries, dest, repository, secret_key)?;
+
+    Ok(entries.len())
+}
+
+/// Returns the relative url of the supplied relative path, percent-encoding each segment
+/// # Arguments
+/// * `path` - The relative path
+fn relative_url(path: &Path) -> String {
+    path.iter()
+        .map(|segment| percent_encode(segment.as_bytes(), SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Collects the paths of all `.lfpkg` files in `dir` recursively, sorted by name
+/// # Arguments
+/// * `dir` - The directory to search
+/// * `files` - The vector to append the paths to
+fn find_packages(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), LError> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find_packages(&path, files)?;
+        } else if path.extension().map(|e| e == "lfpkg").unwrap_or(false) {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::mirror::Mirror;
+    use crate::package::builder::build_package;
+    use crate::util::compression::Compression;
+
+    const SECRET: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+    /// Builds the package `name-1.0` into `dest`
+    fn build(dir: &Path, name: &str, dest: &Path) {
+        let staging = dir.join("staging");
+        std::fs::create_dir_all(&staging).unwrap();
+        std::fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        let manifest = PackageManifest {
+            name: name.to_string(),
+            version: "1.0".to_string(),
+            real_version: 1,
+            description: String::new(),
+            dependencies: Vec::new(),
+            files: Vec::new(),
+        };
+        build_package(&manifest, &staging, None, dest, Compression::None).unwrap();
+    }
+
+    #[test]
+    fn relative_urls_are_percent_encoded() {
+        assert_eq!(
+            relative_url(Path::new("extra/c++/foo bar#1%.lfpkg")),
+            "extra/c++/foo%20bar%231%25.lfpkg"
+        );
+    }
+
+    /// Generates a package list for packages with names needing encoding and loads it
+    /// using a mirror, optionally signing it
+    fn round_trip(secret_key: Option<&str>) {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        let files = [
+            repo.join("core").join("plain-1.0.lfpkg"),
+            repo.join("extra stuff").join("odd #1?.lfpkg"),
+        ];
+        build(dir.path(), "plain", &files[0]);
+        build(dir.path(), "odd", &files[1]);
+        // Only one of them has a signature to list, the other one's is derived from its url
+        util::signature::sign_file(&files[1], SECRET).unwrap();
+
+        let list = repo.join("packagelist.json");
+        assert_eq!(
+            generate_package_list(&repo, &list, "test", secret_key).unwrap(),
+            2
+        );
+
+        let config = Config {
+            root: Some(dir.path().join("root")),
+            render_bar: false,
+            ..Default::default()
+        };
+        let mut mirror = Mirror::new("test", &list.to_string_lossy());
+        match secret_key {
+            Some(secret_key) => {
+                mirror.keys = vec![util::signature::public_key(secret_key).unwrap()]
+            }
+            None => mirror.allow_unsigned = true,
+        }
+        mirror.update(&config).unwrap();
+        mirror.load(&config).unwrap();
+
+        for (name, file) in [("plain", &files[0]), ("odd", &files[1])] {
+            let package = mirror.find_package(name).unwrap();
+            let crate::package::PackageVariant::Remote(remote) = package.as_ref() else {
+                panic!("{} is not a remote package", name);
+            };
+            assert_eq!(
+                crate::download::local_path(remote.get_url()).as_ref(),
+                Some(file)
+            );
+            assert_eq!(
+                crate::download::local_path(&remote.get_signature_url()),
+                Some(util::signature::signature_path(file))
+            );
+        }
+    }
+
+    #[test]
+    fn generated_list_round_trips_through_mirror() {
+        round_trip(None);
+    }
+
+    #[test]
+    fn signed_generated_list_round_trips_through_mirror() {
+        round_trip(Some(SECRET));
+    }
+}
@@ -0,0 +1,112 @@
+//! The envelope a mirror responds to package list requests with
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::error::*;
+
+/// The status of a response delivering a package list
+pub const STATUS_OK: &str = "ok";
+
+/// The response of a mirror to a package list request
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ListResponse {
+    /// The status of the response, `ok` if it delivers a package list (default: `ok`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// The package list, serialized to a string (or the list itself for compatibility)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<serde_json::Value>,
+    /// The hex encoded Ed25519 signature of the bytes of the `payload` string
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// The contents of a payload: The packages along with information about them,
+/// so the signature covers both
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ListPayload<T> {
+    /// Information about the package list
+    #[serde(default)]
+    pub metadata: ListMetadata,
+    pub packages: T,
+}
+
+/// Information about a package list
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ListMetadata {
+    /// The name of the repository the package list describes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repository: Option<String>,
+    /// The time the package list has been generated at, in seconds since the unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generated: Option<u64>,
+}
+
+impl<T: DeserializeOwned> ListPayload<T> {
+    /// Parses the supplied payload, which is either an object holding the packages
+    /// and their metadata or, for compatibility, the list of packages itself
+    /// # Arguments
+    /// * `payload` - The serialized payload
+    pub fn parse(payload: &str) -> Result<Self, serde_json::Error> {
+        let value: serde_json::Value = serde_json::from_str(payload)?;
+        if value.is_array() {
+            Ok(Self {
+                metadata: ListMetadata::default(),
+                packages: serde_json::from_value(value)?,
+            })
+        } else {
+            serde_json::from_value(value)
+        }
+    }
+}
+
+impl ListResponse {
+    /// Parses a response from the supplied JSON data
+    /// # Arguments
+    /// * `data` - The body of the response
+    pub fn parse(data: &[u8]) -> Result<Self, LError> {
+        serde_json::from_slice(data).map_err(|e| {
+            LError::new(
+                LErrorClass::InvalidPackageList,
+                &format!("Malformed response: {}", e),
+            )
+        })
+    }
+
+    /// Checks the status of the response and returns the serialized package list
+    /// and the signature covering it
+    ///
+    /// The payload is the package list serialized to a string, whose bytes are exactly what the
+    /// signature covers, see [`ListPayload`]. A payload that is the list itself is accepted for compatibility, but as
+    /// its bytes are lost when parsing the response, it is treated as unsigned.
+    /// The packages in the list are validated by the mirror.
+    pub fn get_payload(&self) -> Result<(String, Option<String>), LError> {
+        if let Some(status) = &self.status {
+            if !status.eq_ignore_ascii_case(STATUS_OK) {
+                return Err(LError::new(
+                    LErrorClass::InvalidPackageList,
+                    &format!("Mirror responded with status '{}'", status),
+                ));
+            }
+        }
+
+        match &self.payload {
+            Some(serde_json::Value::String(payload)) => {
+                Ok((payload.clone(), self.signature.clone()))
+            }
+            Some(payload) if payload.is_array() => {
+                if self.signature.is_some() {
+                    warn!("Ignoring the signature of a package list that is not a string");
+                }
+                Ok((payload.to_string(), None))
+            }
+            Some(_) => Err(LError::new(
+                LErrorClass::InvalidPackageList,
+                "The payload is not a list of packages",
+            )),
+            None => Err(LError::new(
+                LErrorClass::InvalidPackageList,
+                "The response has no payload",
+            )),
+        }
+    }
+}
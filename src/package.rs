@@ -1,8 +1,10 @@
 //! A package is the main work horse of the leaf package manager.
 //! Every variant of a package implements the trait Package.
 
+pub mod builder;
 pub mod installed;
 pub mod local;
+pub mod manifest;
 pub mod remote;
 
 mod packageref;
@@ -211,9 +213,9 @@ pub trait Package: Clone {
     /// Set the dependencies needed by this package
     fn set_dependencies(&mut self, dependencies: Dependencies);
 
-    /// Get the package MD5 hash
+    /// Get the package hash tagged with its algorithm (`<algorithm>:<hex digest>`)
     fn get_hash(&self) -> String;
-    /// Set the package MD5 hash
+    /// Set the package hash tagged with its algorithm (`<algorithm>:<hex digest>`)
     fn set_hash(&mut self, hash: &str);
 
     /// Get the full name for the package: `<name>-<version>` (E.g: glibc-2.3)
@@ -0,0 +1,300 @@
+//! This module provides building `.lfpkg` archives from a staging directory
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tar::{Builder, EntryType, Header};
+
+use super::manifest::{PackageManifest, MANIFEST_FILE};
+use crate::error::*;
+use crate::util::{
+    self,
+    compression::Compression,
+    fs::{FSEntry, FSEntryType},
+};
+
+/// Builds a reproducible `.lfpkg` archive at `dest` and returns its hash
+///
+/// The contents of `staging` become the `data/` directory of the package, the files in `scripts`
+/// its `scripts/` directory. The manifest gets written with the file list filled in.
+/// The manifest and the scripts precede the data, so they can be read before deploying it.
+/// Entries are written sorted by name, owned by `0:0` and with their mtime set to
+/// `SOURCE_DATE_EPOCH` (or `0` if it is not set), so the same inputs produce the same archive.
+/// # Arguments
+/// * `manifest` - The metadata of the package, its `files` get replaced
+/// * `staging` - The directory containing the files to package
+/// * `scripts` - The directory containing the package scripts, if any
+/// * `dest` - The path to write the package to
+/// * `compression` - The compression to use for the archive
+pub fn build_package(
+    manifest: &PackageManifest,
+    staging: &Path,
+    scripts: Option<&Path>,
+    dest: &Path,
+    compression: Compression,
+) -> Result<String, LError> {
+    let full_name = PathBuf::from(format!("{}-{}", manifest.name, manifest.version));
+    let context = format!(
+        "When building package {} at {}",
+        full_name.to_string_lossy(),
+        dest.to_string_lossy()
+    );
+    let mtime = source_date_epoch();
+
+    // Index the staging directory and fill in the file list
+    debug!("Indexing staging directory {}", staging.to_string_lossy());
+    let files = util::fs::index(staging).err_prepend(&context)?;
+    let mut manifest = manifest.clone();
+    manifest.files = Vec::new();
+    list_files(&files, Path::new(""), &mut manifest.files);
+    let manifest_data = serde_json::to_vec_pretty(&manifest).map_err(|e| {
+        LError::new(
+            LErrorClass::JSON,
+            &format!("{}: When serializing manifest: {}", context, e),
+        )
+    })?;
+
+    // Write to a temporary file first to never leave a partial package behind,
+    // it gets removed if building fails
+    let temp = util::fs::TempFile::new(dest);
+    let file = File::create(temp.path()).err_prepend(&context)?;
+    let mut builder = Builder::new(compression.encoder(file).err_prepend(&context)?);
+
+    append_dir(&mut builder, &full_name, 0o755, mtime).err_prepend(&context)?;
+
+    let mut header = new_header(EntryType::Regular, 0o644, mtime);
+    header.set_size(manifest_data.len() as u64);
+    builder
+        .append_data(
+            &mut header,
+            full_name.join(MANIFEST_FILE),
+            manifest_data.as_slice(),
+        )
+        .err_prepend(&context)?;
+
+    if let Some(scripts) = scripts {
+        let scripts_dir = full_name.join("scripts");
+        append_dir(&mut builder, &scripts_dir, 0o755, mtime).err_prepend(&context)?;
+
+        let mut entries = std::fs::read_dir(scripts)
+            .err_prepend(&context)?
+            .collect::<Result<Vec<_>, _>>()
+            .err_prepend(&context)?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            if !entry.file_type().err_prepend(&context)?.is_file() {
+                continue;
+            }
+            append_file(
+                &mut builder,
+                &entry.path(),
+                &scripts_dir.join(entry.file_name()),
+                0o755,
+                mtime,
+            )
+            .err_prepend(&context)?;
+        }
+    }
+
+    let data_dir = full_name.join("data");
+    append_dir(&mut builder, &data_dir, 0o755, mtime).err_prepend(&context)?;
+    append_entries(
+        &mut builder,
+        &files,
+        &mut staging.to_path_buf(),
+        &mut data_dir.clone(),
+        &data_dir,
+        mtime,
+    )
+    .err_prepend(&context)?;
+
+    let file = builder
+        .into_inner()
+        .err_prepend(&context)?
+        .finish()
+        .err_prepend(&context)?;
+    file.sync_all().err_prepend(&context)?;
+    temp.persist(dest).err_prepend(&context)?;
+
+    util::hash::hash_file(dest).err_prepend(&context)
+}
+
+/// Appends the supplied entries recursively to the archive
+/// # Arguments
+/// * `builder` - The archive to append to
+/// * `entries` - The entries to append
+/// * `src` - The path of the directory containing the entries
+/// * `dest` - The path of the directory in the archive to append the entries to
+/// * `data_dir` - The `data/` directory in the archive hard links are relative to
+/// * `mtime` - The modification time to use for all entries
+fn append_entries<W: std::io::Write>(
+    builder: &mut Builder<W>,
+    entries: &[FSEntry],
+    src: &mut PathBuf,
+    dest: &mut PathBuf,
+    data_dir: &Path,
+    mtime: u64,
+) -> Result<(), LError> {
+    for entry in entries {
+        src.push(&entry.name);
+        dest.push(&entry.name);
+
+        match entry.entry_type {
+            FSEntryType::Directory => {
+                append_dir(builder, dest, entry.mode, mtime)?;
+                append_entries(builder, &entry.children, src, dest, data_dir, mtime)?;
+            }
+            FSEntryType::File => append_file(builder, src, dest, entry.mode, mtime)?,
+            FSEntryType::Symlink => {
+                let mut header = new_header(EntryType::Symlink, 0o777, mtime);
+                builder.append_link(&mut header, &dest, src.read_link()?)?;
+            }
+            FSEntryType::HardLink => {
+                let target = match &entry.link_target {
+                    Some(target) => data_dir.join(target),
+                    None => {
+                        return Err(LError::new(
+                            LErrorClass::IO(std::io::ErrorKind::InvalidData),
+                            &format!("Hard link {} has no target", src.to_string_lossy()),
+                        ))
+                    }
+                };
+                let mut header = new_header(EntryType::Link, entry.mode, mtime);
+                builder.append_link(&mut header, &dest, target)?;
+            }
+            FSEntryType::CharDevice | FSEntryType::BlockDevice | FSEntryType::FIFO => {
+                let entry_type = match entry.entry_type {
+                    FSEntryType::CharDevice => EntryType::Char,
+                    FSEntryType::BlockDevice => EntryType::Block,
+                    _ => EntryType::Fifo,
+                };
+                let mut header = new_header(entry_type, entry.mode, mtime);
+                if entry_type != EntryType::Fifo {
+                    header.set_device_major(libc::major(entry.rdev))?;
+                    header.set_device_minor(libc::minor(entry.rdev))?;
+                }
+                builder.append_data(&mut header, &dest, std::io::empty())?;
+            }
+            FSEntryType::Socket => {
+                warn!("Skipping socket {}", src.to_string_lossy());
+            }
+        }
+
+        src.pop();
+        dest.pop();
+    }
+
+    Ok(())
+}
+
+/// Appends a directory entry to the archive
+/// # Arguments
+/// * `builder` - The archive to append to
+/// * `path` - The path of the directory in the archive
+/// * `mode` - The permissions of the directory
+/// * `mtime` - The modification time of the directory
+fn append_dir<W: std::io::Write>(
+    builder: &mut Builder<W>,
+    path: &Path,
+    mode: u32,
+    mtime: u64,
+) -> Result<(), LError> {
+    let mut header = new_header(EntryType::Directory, mode, mtime);
+    builder.append_data(&mut header, path, std::io::empty())?;
+    Ok(())
+}
+
+/// Appends the contents of the regular file at `src` to the archive
+/// # Arguments
+/// * `builder` - The archive to append to
+/// * `src` - The file to append
+/// * `path` - The path of the file in the archive
+/// * `mode` - The permissions of the file
+/// * `mtime` - The modification time of the file
+fn append_file<W: std::io::Write>(
+    builder: &mut Builder<W>,
+    src: &Path,
+    path: &Path,
+    mode: u32,
+    mtime: u64,
+) -> Result<(), LError> {
+    let file = File::open(src)?;
+    let size = file.metadata()?.len();
+    let mut header = new_header(EntryType::Regular, mode, mtime);
+    header.set_size(size);
+    builder.append_data(&mut header, path, file.take(size))?;
+    Ok(())
+}
+
+/// Creates a new header with normalized ownership
+/// # Arguments
+/// * `entry_type` - The type of the entry
+/// * `mode` - The permissions of the entry
+/// * `mtime` - The modification time of the entry
+fn new_header(entry_type: EntryType, mode: u32, mtime: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(mtime);
+    header.set_size(0);
+    header
+}
+
+/// Lists the paths of all the supplied entries recursively, skipping sockets
+/// # Arguments
+/// * `entries` - The entries to list
+/// * `prefix` - The path of the directory containing the entries
+/// * `files` - The vector to append the paths to
+fn list_files(entries: &[FSEntry], prefix: &Path, files: &mut Vec<String>) {
+    for entry in entries
+        .iter()
+        .filter(|e| e.entry_type != FSEntryType::Socket)
+    {
+        let path = prefix.join(&entry.name);
+        files.push(path.to_string_lossy().to_string());
+        list_files(&entry.children, &path, files);
+    }
+}
+
+/// Returns the timestamp to use for all entries, `SOURCE_DATE_EPOCH` if set, else `0`
+fn source_date_epoch() -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_build_leaves_no_temporary_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let staging = dir.path().join("staging");
+        let dest = dir.path().join("test-1.0.lfpkg");
+        std::fs::create_dir(&staging).unwrap();
+        let manifest = PackageManifest {
+            name: "test".to_string(),
+            version: "1.0".to_string(),
+            real_version: 1,
+            description: String::new(),
+            dependencies: Vec::new(),
+            files: Vec::new(),
+        };
+
+        // The missing scripts directory fails the build after the archive has been created
+        let res = build_package(
+            &manifest,
+            &staging,
+            Some(&dir.path().join("missing")),
+            &dest,
+            Compression::None,
+        );
+
+        assert!(res.is_err());
+        assert!(!dest.exists());
+        assert!(!util::fs::temp_path(&dest).exists());
+    }
+}
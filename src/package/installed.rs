@@ -68,16 +68,18 @@ impl InstalledPackage {
         name: &str,
     ) -> Result<Option<Self>, LError> {
         let mut stmt = transaction.prepare(
-            "SELECT name, version, real_version, description, hash FROM packages WHERE name = ?",
+            "SELECT name, version, real_version, description, hash, hash_algorithm FROM packages WHERE name = ?",
         )?;
 
         let mut packages_iter = stmt.query_map([name], |row| {
+            let hash: String = row.get(4)?;
+            let algorithm: String = row.get(5)?;
             let res = Self {
                 name: row.get(0)?,
                 version: row.get(1)?,
                 real_version: row.get(2)?,
                 description: row.get(3)?,
-                hash: row.get(4)?,
+                hash: format!("{}:{}", algorithm, hash),
                 dependencies: Dependencies::Unresolved(vec![]),
                 files: Vec::new(),
             };
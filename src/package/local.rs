@@ -1,13 +1,15 @@
+use std::fs::File;
 use std::path::{Path, PathBuf};
 
 use crate::{
-    config::Config,
+    config::{Config, DeployStrategy},
     error::*,
     package::installed::*,
-    util::{self, fs::FSEntry},
+    util::{self, fs::FSEntry, scripts::ScriptHook, triggers::PendingTriggers},
 };
 use serde::Deserialize;
 
+use super::manifest::PackageManifest;
 use super::remote::RemotePackage;
 pub use super::Dependencies;
 use super::Package;
@@ -30,49 +32,216 @@ pub struct LocalPackage {
 
 impl LocalPackage {
     /// Deploys this package to the system using the provided config
+    ///
+    /// The signature of the package gets verified before it is extracted.
+    /// The `preinstall` and `postinstall` scripts of the package are run before and after deploying its files
     /// # Arguments
     /// * `config` - The config to reference for deployment
-    /// * `db_con` - The database connection to use for inserting this package
-    pub fn deploy(self, config: &Config) -> Result<InstalledPackage, LError> {
-        debug!("Extracting package {}", self.get_fq_name());
-        self.extract(config)?;
-
-        // Index the package contents
-        let mut files: Vec<FSEntry> = Vec::new();
-        debug!("Indexing package {}", self.get_fq_name());
-        let start = Instant::now();
-        files.append(&mut util::fs::index(&self.get_data_dir(config))?);
-        debug!("Took {} ms", start.elapsed().as_millis());
+    /// * `triggers` - The pending triggers to activate for the deployed files
+    pub fn deploy(
+        self,
+        config: &Config,
+        triggers: &mut PendingTriggers,
+    ) -> Result<InstalledPackage, LError> {
+        let stream = config.stream_deploy.unwrap_or(false);
+
+        // Deploy from the handle that has been verified, not from whatever is at the path now
+        let file = self.verify_signature(config)?;
+
+        let (installed_pkg, post_install) = match stream {
+            // Streaming collects the scripts and runs `preinstall` while reading the archive
+            true => self.stream_to_root(config, triggers, file)?,
+            false => {
+                debug!("Extracting package {}", self.get_fq_name());
+                self.extract(config, file)?;
+
+                let post_install = self.read_script(config, ScriptHook::PostInstall)?;
+                self.run_script(config, ScriptHook::PreInstall)?;
+
+                // Index the package contents
+                let mut files: Vec<FSEntry> = Vec::new();
+                debug!("Indexing package {}", self.get_fq_name());
+                let start = Instant::now();
+                files.append(&mut util::fs::index(&self.get_data_dir(config))?);
+                debug!("Took {} ms", start.elapsed().as_millis());
+
+                debug!(
+                    "Copying package {} to root {:?}...",
+                    self.get_fq_name(),
+                    config.get_root()
+                );
+                let start = Instant::now();
+                let installed_pkg = self.copy_to_root(config, files, triggers)?;
+                debug!("Took {} ms", start.elapsed().as_millis());
+
+                (installed_pkg, post_install)
+            }
+        };
+
+        if let Some(script) = post_install {
+            util::scripts::run_script(
+                config,
+                &script,
+                ScriptHook::PostInstall,
+                &installed_pkg.get_name(),
+                &installed_pkg.get_version(),
+            )?;
+        }
+
+        Ok(installed_pkg)
+    }
 
+    /// Deploys the package contents to the new root directly from its archive, without
+    /// extracting it to the packages directory first
+    ///
+    /// The `preinstall` script is run before the first file gets deployed,
+    /// the `postinstall` script is returned for running it afterwards
+    /// # Arguments
+    /// * `config` - The config to reference for deployment
+    /// * `triggers` - The pending triggers to activate for the deployed files
+    /// * `file` - The opened package file, see `verify_signature()`
+    fn stream_to_root(
+        self,
+        config: &Config,
+        triggers: &mut PendingTriggers,
+        file: File,
+    ) -> Result<(InstalledPackage, Option<Vec<u8>>), LError> {
         debug!(
-            "Copying package {} to root {:?}...",
+            "Streaming package {} to root {:?}...",
             self.get_fq_name(),
             config.get_root()
         );
+        if config.deploy_strategy != DeployStrategy::Copy {
+            warn!(
+                "Ignoring deploy strategy {:?} when streaming package {}",
+                config.deploy_strategy,
+                self.get_fq_name()
+            );
+        }
+
         let start = Instant::now();
-        let installed_pkg = self.copy_to_root(config, files)?;
+        let (files, mut scripts) = util::deploy::deploy_archive(
+            file,
+            Path::new(&self.get_full_name()),
+            config.get_root(),
+            &util::fs::DeployOptions::from_config(config),
+            triggers,
+            &|path| config.callbacks.file_exists(config, path),
+            |scripts| match scripts.get(ScriptHook::PreInstall.file_name()) {
+                Some(script) => util::scripts::run_script(
+                    config,
+                    script,
+                    ScriptHook::PreInstall,
+                    &self.get_name(),
+                    &self.get_version(),
+                ),
+                None => Ok(()),
+            },
+        )
+        .err_prepend(&format!(
+            "When deploying files of package {}",
+            self.get_fq_name()
+        ))?;
         debug!("Took {} ms", start.elapsed().as_millis());
 
-        Ok(installed_pkg)
+        let post_install = scripts.remove(ScriptHook::PostInstall.file_name());
+        Ok((InstalledPackage::from_local(self, files), post_install))
+    }
+
+    /// Verifies the detached signature of the package file (`<file>.sig`) against the keyring
+    /// and returns the opened package file, rewound to its start
+    ///
+    /// Deploying from the returned file deploys the verified data, even if the package file
+    /// gets replaced meanwhile. Unsigned packages are refused unless `allow_unsigned_packages`
+    /// is enabled in the config
+    /// # Arguments
+    /// * `config` - The config to get the keyring from
+    pub fn verify_signature(&self, config: &Config) -> Result<File, LError> {
+        debug!("Verifying signature of package {}", self.get_fq_name());
+        let context = format!("When verifying package {}", self.get_fq_name());
+
+        let mut file = File::open(&self.file_path).err_prepend(&context)?;
+        let keys = config.get_keyring()?.get_package_keys();
+        util::signature::check_file(
+            &self.file_path,
+            &mut file,
+            &keys,
+            config.allow_unsigned_packages.unwrap_or(false),
+            &format!("package {}", self.get_fq_name()),
+        )?;
+
+        Ok(file)
+    }
+
+    /// Reads the script for the supplied hook shipped with this package in `scripts/` next to `data/`
+    /// from the extracted package tree, returns `None` if the package has no such script
+    ///
+    /// Streaming deployment never extracts the package: It takes the scripts from the same pass
+    /// over the verified archive that deploys the files, see `stream_to_root()`
+    /// # Arguments
+    /// * `config` - The config to refer to for paths
+    /// * `hook` - The hook to read the script of
+    pub fn read_script(
+        &self,
+        config: &Config,
+        hook: ScriptHook,
+    ) -> Result<Option<Vec<u8>>, LError> {
+        let path = self
+            .get_extracted_dir(config)
+            .join("scripts")
+            .join(hook.file_name());
+        match path.exists() {
+            true => Ok(Some(std::fs::read(&path).err_prepend(&format!(
+                "When reading {} script of package {}",
+                hook,
+                self.get_fq_name()
+            ))?)),
+            false => Ok(None),
+        }
+    }
+
+    /// Runs the script for the supplied hook if this package ships one
+    /// # Arguments
+    /// * `config` - The config to use for running the script
+    /// * `hook` - The hook to run the script of
+    pub fn run_script(&self, config: &Config, hook: ScriptHook) -> Result<(), LError> {
+        match self.read_script(config, hook)? {
+            Some(script) => util::scripts::run_script(
+                config,
+                &script,
+                hook,
+                &self.get_name(),
+                &self.get_version(),
+            ),
+            None => Ok(()),
+        }
     }
 
     /// Copies the package contents to the new root using the supplied config
     /// # Arguments
     /// * `config` - The configuration to use for copying
     /// * `files` - The vector of files to copy
+    /// * `triggers` - The pending triggers to activate for the copied files
     fn copy_to_root(
         self,
         config: &Config,
         files: Vec<FSEntry>,
+        triggers: &mut PendingTriggers,
     ) -> Result<InstalledPackage, LError> {
         let mut cur_src: PathBuf = self.get_data_dir(config);
         let mut cur_dest: PathBuf = PathBuf::from(config.get_root());
 
         // Copy the fsentries
         let mut iter = files.iter();
-        util::fs::copy_recursive(&mut cur_src, &mut cur_dest, &mut iter, &|path| {
-            config.callbacks.file_exists(config, path)
-        })
+        let options = util::fs::DeployOptions::from_config(config);
+        util::fs::copy_recursive(
+            &mut cur_src,
+            &mut cur_dest,
+            &mut iter,
+            &options,
+            triggers,
+            &|path| config.callbacks.file_exists(config, path),
+        )
         .err_prepend(&format!(
             "When copying files of package {}",
             self.get_fq_name()
@@ -86,7 +255,8 @@ impl LocalPackage {
     /// Extracts the local package into the packages_dir
     /// # Arguments
     /// * `config` - The config to refer to for paths
-    pub fn extract(&self, config: &Config) -> Result<(), LError> {
+    /// * `file` - The opened package file, see `verify_signature()`
+    pub fn extract(&self, config: &Config, file: File) -> Result<(), LError> {
         let target_dir = config.get_packages_dir();
         let target_path = self.get_extracted_dir(config);
 
@@ -108,11 +278,11 @@ impl LocalPackage {
 
         let start = Instant::now();
         util::extract(
-            &config
-                .get_download_dir()
-                .join(self.get_full_name() + ".lfpkg"),
+            file,
             &config.get_packages_dir(),
-        )?;
+            Path::new(&self.get_full_name()),
+        )
+        .err_prepend(&format!("When extracting package {}", self.get_fq_name()))?;
         debug!("Took {} ms", start.elapsed().as_millis());
 
         Ok(())
@@ -135,6 +305,114 @@ impl LocalPackage {
         }
     }
 
+    /// Creates a local package from the `.lfpkg` file at `file_path` using only its embedded manifest
+    /// # Arguments
+    /// * `file_path` - The path to the .lfpkg file
+    pub fn from_file(file_path: &Path) -> Result<LocalPackage, LError> {
+        let manifest = PackageManifest::from_archive(file_path)?;
+        let hash = util::hash::hash_file(file_path)?;
+
+        Ok(Self {
+            name: manifest.name,
+            version: manifest.version,
+            real_version: manifest.real_version,
+            description: manifest.description,
+            dependencies: Dependencies::Unresolved(manifest.dependencies),
+            hash,
+            file_path: file_path.to_path_buf(),
+        })
+    }
+
+    /// Creates a local package from the `.lfpkg` file at `file_path` using its embedded manifest
+    /// and makes sure it describes the same package as the supplied mirror entry
+    ///
+    /// Returns `LErrorClass::HashMismatch` if the file does not have the hash of the mirror entry
+    /// and `LErrorClass::InvalidManifest` if the manifest describes another package.
+    /// Packages without a manifest are described by the mirror entry alone
+    /// # Arguments
+    /// * `remote` - The mirror entry the file has been fetched for
+    /// * `file_path` - The path to the .lfpkg file
+    pub fn from_file_checked(
+        remote: &RemotePackage,
+        file_path: &Path,
+    ) -> Result<LocalPackage, LError> {
+        // The mirror may use a different hash algorithm
+        let (algorithm, _) = util::hash::split_hash(&remote.get_hash())?;
+        let hash = util::hash::hash_file_with(file_path, algorithm)?;
+        if hash != remote.get_hash() {
+            return Err(LError::new(
+                LErrorClass::HashMismatch,
+                &format!(
+                    "Package {}: expected {}, got {}",
+                    file_path.to_string_lossy(),
+                    remote.get_hash(),
+                    hash
+                ),
+            ));
+        }
+
+        let manifest = match PackageManifest::find_in_archive(file_path)? {
+            Some(manifest) => manifest,
+            None => {
+                warn!(
+                    "Package {} has no manifest, using the metadata of the mirror",
+                    remote.get_fq_name()
+                );
+                return Ok(Self::from_remote(remote, file_path, &hash));
+            }
+        };
+        let local = Self {
+            name: manifest.name,
+            version: manifest.version,
+            real_version: manifest.real_version,
+            description: manifest.description,
+            dependencies: Dependencies::Unresolved(manifest.dependencies),
+            hash,
+            file_path: file_path.to_path_buf(),
+        };
+
+        let mismatch = |field: &str, local: &str, remote: &str| {
+            Err(LError::new(
+                LErrorClass::InvalidManifest,
+                &format!(
+                    "Package {} has {} '{}' but the mirror expects '{}'",
+                    file_path.to_string_lossy(),
+                    field,
+                    local,
+                    remote
+                ),
+            ))
+        };
+
+        if local.get_name() != remote.get_name() {
+            return mismatch("name", &local.get_name(), &remote.get_name());
+        }
+        if local.get_version() != remote.get_version() {
+            return mismatch("version", &local.get_version(), &remote.get_version());
+        }
+        if local.get_real_version() != remote.get_real_version() {
+            return mismatch(
+                "real version",
+                &local.get_real_version().to_string(),
+                &remote.get_real_version().to_string(),
+            );
+        }
+
+        let mut local_deps = local.get_dependencies().get_unresolved()?.clone();
+        let mut remote_deps = remote.get_dependencies().get_unresolved()?.clone();
+        local_deps.sort();
+        remote_deps.sort();
+        if local_deps != remote_deps {
+            return mismatch(
+                "dependencies",
+                &local_deps.join(", "),
+                &remote_deps.join(", "),
+            );
+        }
+
+        Ok(local)
+    }
+
     /// Returns the directory that results when the package is extracted
     ///
     /// Example: package `glibc-2.36` -> `<package_dir/glibc-2.36/`
@@ -156,3 +434,163 @@ impl LocalPackage {
             .join("data")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::compression::Compression;
+
+    /// Builds the package `test-1.0` shipping `file` with the supplied contents into `dir`
+    /// and returns its path and hash
+    fn build(dir: &Path, data: &str) -> (PathBuf, String) {
+        let staging = dir.join("staging");
+        std::fs::create_dir_all(&staging).unwrap();
+        std::fs::write(staging.join("file"), data).unwrap();
+
+        let manifest = PackageManifest {
+            name: "test".to_string(),
+            version: "1.0".to_string(),
+            real_version: 1,
+            description: "A test package".to_string(),
+            dependencies: vec!["dep".to_string()],
+            files: Vec::new(),
+        };
+        let path = dir.join("test-1.0.lfpkg");
+        let hash = crate::package::builder::build_package(
+            &manifest,
+            &staging,
+            None,
+            &path,
+            Compression::None,
+        )
+        .unwrap();
+
+        (path, hash)
+    }
+
+    /// Creates the mirror entry of `test` with the supplied version and hash
+    fn remote(version: &str, hash: &str) -> RemotePackage {
+        serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "version": version,
+            "real_version": 1,
+            "description": "A test package",
+            "dependencies": ["dep"],
+            "hash": hash,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn from_file_checked_accepts_matching_package() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, hash) = build(dir.path(), "data");
+
+        let local = LocalPackage::from_file_checked(&remote("1.0", &hash), &path).unwrap();
+        assert_eq!(local.get_full_name(), "test-1.0");
+        assert_eq!(local.get_hash(), hash);
+    }
+
+    #[test]
+    fn from_file_checked_refuses_other_package() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, hash) = build(dir.path(), "data");
+
+        let err = LocalPackage::from_file_checked(&remote("2.0", &hash), &path).unwrap_err();
+        assert_eq!(err.class, LErrorClass::InvalidManifest);
+    }
+
+    #[test]
+    fn from_file_checked_refuses_hash_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = build(dir.path(), "data");
+
+        let other = util::hash::hash_str("other");
+        let err = LocalPackage::from_file_checked(&remote("1.0", &other), &path).unwrap_err();
+        assert_eq!(err.class, LErrorClass::HashMismatch);
+    }
+
+    /// Returns a config working in the supplied directory
+    fn config(dir: &Path) -> Config {
+        Config {
+            root: Some(dir.join("root")),
+            render_bar: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unsigned_packages_follow_the_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = build(dir.path(), "data");
+        let local = LocalPackage::from_file(&path).unwrap();
+        let mut config = config(dir.path());
+
+        let err = local.verify_signature(&config).unwrap_err();
+        assert_eq!(err.class, LErrorClass::InvalidSignature);
+
+        config.allow_unsigned_packages = Some(true);
+        assert!(local.verify_signature(&config).is_ok());
+    }
+
+    #[test]
+    fn verified_package_is_extracted_from_the_same_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = build(dir.path(), "verified");
+        let other = tempfile::tempdir().unwrap();
+        let (other_path, _) = build(other.path(), "replaced");
+        let local = LocalPackage::from_file(&path).unwrap();
+        let mut config = config(dir.path());
+        config.allow_unsigned_packages = Some(true);
+        util::ensure_dirs(&config).unwrap();
+
+        let file = local.verify_signature(&config).unwrap();
+        std::fs::rename(&other_path, &path).unwrap();
+        local.extract(&config, file).unwrap();
+
+        let data = std::fs::read_to_string(local.get_data_dir(&config).join("file")).unwrap();
+        assert_eq!(data, "verified");
+    }
+
+    #[test]
+    fn scripts_are_not_read_from_the_package_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let staging = dir.path().join("staging");
+        let scripts = dir.path().join("scripts");
+        std::fs::create_dir_all(&staging).unwrap();
+        std::fs::create_dir_all(&scripts).unwrap();
+        std::fs::write(scripts.join("postinstall"), "true\n").unwrap();
+        let manifest = PackageManifest {
+            name: "test".to_string(),
+            version: "1.0".to_string(),
+            real_version: 1,
+            description: String::new(),
+            dependencies: Vec::new(),
+            files: Vec::new(),
+        };
+        let path = dir.path().join("test-1.0.lfpkg");
+        crate::package::builder::build_package(
+            &manifest,
+            &staging,
+            Some(&scripts),
+            &path,
+            Compression::None,
+        )
+        .unwrap();
+        let local = LocalPackage::from_file(&path).unwrap();
+        let mut config = config(dir.path());
+        config.stream_deploy = Some(true);
+
+        // Streaming takes the scripts from the verified archive while deploying it
+        let script = local.read_script(&config, ScriptHook::PostInstall).unwrap();
+        assert!(script.is_none());
+
+        config.stream_deploy = Some(false);
+        config.allow_unsigned_packages = Some(true);
+        util::ensure_dirs(&config).unwrap();
+        let file = local.verify_signature(&config).unwrap();
+        local.extract(&config, file).unwrap();
+        let script = local.read_script(&config, ScriptHook::PostInstall).unwrap();
+        assert_eq!(script.as_deref(), Some(b"true\n".as_slice()));
+    }
+}
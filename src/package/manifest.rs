@@ -0,0 +1,152 @@
+//! The manifest is the metadata a package carries inside its archive
+//! at `<name>-<version>/package.json`
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use tar::Archive;
+
+use crate::{error::*, util};
+
+/// The file name of the manifest inside the package directory of the archive
+pub const MANIFEST_FILE: &str = "package.json";
+
+/// The metadata a package describes itself with, using the same fields as the mirror package list
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PackageManifest {
+    pub name: String,
+    pub version: String,
+    #[serde(deserialize_with = "crate::util::deserialize_number_from_string")]
+    pub real_version: u64,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    /// The paths of all entries in `data/`, relative to it
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+impl PackageManifest {
+    /// Parses a manifest from the supplied JSON data
+    /// # Arguments
+    /// * `data` - The contents of the manifest file
+    pub fn parse(data: &[u8]) -> Result<Self, LError> {
+        serde_json::from_slice(data).map_err(|e| {
+            LError::new(
+                LErrorClass::JSON,
+                &format!("When parsing package manifest: {}", e),
+            )
+        })
+    }
+
+    /// Reads the manifest from the package archive at `source`,
+    /// failing with `LErrorClass::InvalidManifest` if the archive does not contain one
+    ///
+    /// See `find_in_archive()` for the checks
+    /// # Arguments
+    /// * `source` - The path to the `.lfpkg` file
+    pub fn from_archive(source: &Path) -> Result<Self, LError> {
+        match Self::find_in_archive(source)? {
+            Some(manifest) => Ok(manifest),
+            None => Err(LError::new(
+                LErrorClass::InvalidManifest,
+                &format!(
+                    "Package {} does not contain a {}",
+                    source.to_string_lossy(),
+                    MANIFEST_FILE
+                ),
+            )),
+        }
+    }
+
+    /// Reads the manifest from the package archive at `source`, if it contains one
+    ///
+    /// This makes sure the manifest lives in the directory named after the package
+    /// and that its file list matches the contents of `data/` if it is not empty.
+    /// # Arguments
+    /// * `source` - The path to the `.lfpkg` file
+    pub fn find_in_archive(source: &Path) -> Result<Option<Self>, LError> {
+        let context = format!("When reading manifest of {}", source.to_string_lossy());
+
+        let mut archive = Archive::new(util::compression::open(source).err_prepend(&context)?);
+
+        let mut manifest: Option<(String, Self)> = None;
+        let mut files: HashSet<(String, PathBuf)> = HashSet::new();
+
+        for entry in archive.entries().err_prepend(&context)? {
+            let mut entry = entry.err_prepend(&context)?;
+
+            let path: Vec<String> = entry
+                .path()
+                .err_prepend(&context)?
+                .components()
+                .filter_map(|c| match c {
+                    Component::Normal(n) => Some(n.to_string_lossy().to_string()),
+                    _ => None,
+                })
+                .collect();
+
+            match path.as_slice() {
+                [dir, file] if file == MANIFEST_FILE => {
+                    let mut data: Vec<u8> = Vec::new();
+                    std::io::Read::read_to_end(&mut entry, &mut data).err_prepend(&context)?;
+                    manifest = Some((dir.clone(), Self::parse(&data).err_prepend(&context)?));
+                }
+                [dir, data, rest @ ..] if data == "data" && !rest.is_empty() => {
+                    files.insert((dir.clone(), rest.iter().collect()));
+                }
+                _ => {}
+            }
+        }
+
+        let (dir, manifest) = match manifest {
+            Some(m) => m,
+            None => return Ok(None),
+        };
+
+        let full_name = format!("{}-{}", manifest.name, manifest.version);
+        if dir != full_name {
+            return Err(LError::new(
+                LErrorClass::InvalidManifest,
+                &format!(
+                    "Manifest of {} describes {} but lives in {}",
+                    source.to_string_lossy(),
+                    full_name,
+                    dir
+                ),
+            ));
+        }
+
+        if !manifest.files.is_empty() {
+            let listed: HashSet<PathBuf> = manifest.files.iter().map(PathBuf::from).collect();
+            let contained: HashSet<PathBuf> = files
+                .into_iter()
+                .filter(|(d, _)| d == &dir)
+                .map(|(_, f)| f)
+                .collect();
+
+            if let Some(missing) = listed.difference(&contained).next() {
+                return Err(LError::new(
+                    LErrorClass::InvalidManifest,
+                    &format!(
+                        "Manifest of {} lists {} which is missing from the archive",
+                        full_name,
+                        missing.to_string_lossy()
+                    ),
+                ));
+            }
+            if let Some(unlisted) = contained.difference(&listed).next() {
+                return Err(LError::new(
+                    LErrorClass::InvalidManifest,
+                    &format!(
+                        "Archive of {} contains {} which is not listed in its manifest",
+                        full_name,
+                        unlisted.to_string_lossy()
+                    ),
+                ));
+            }
+        }
+
+        Ok(Some(manifest))
+    }
+}
@@ -29,9 +29,9 @@ pub trait PackageRefTrait: Clone {
     /// Set the dependencies needed by this package
     fn set_dependencies(&mut self, dependencies: Dependencies);
 
-    /// Get the package MD5 hash
+    /// Get the package hash tagged with its algorithm (`<algorithm>:<hex digest>`)
     fn get_hash(&self) -> String;
-    /// Set the package MD5 hash
+    /// Set the package hash tagged with its algorithm (`<algorithm>:<hex digest>`)
     fn set_hash(&mut self, hash: &str);
 
     /// Get the full name for the package: `<name>-<version>` (E.g: glibc-2.3)
@@ -1,5 +1,7 @@
+use indicatif::HumanBytes;
 use serde::Deserialize;
-use std::io::Write;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::RwLock;
 
 use super::local::LocalPackage;
@@ -11,7 +13,7 @@ use crate::config::Config;
 use crate::download::*;
 use crate::error::*;
 use crate::util;
-use crate::{usererr, usermsg};
+use crate::{usererr, usermsg, userwarn};
 use std::sync::Arc;
 
 /// A remote package is a package available at a mirror for downloading
@@ -25,12 +27,70 @@ pub struct RemotePackage {
     description: String,
     #[serde(deserialize_with = "crate::package::Dependencies::deserialize_unresolved")]
     dependencies: Dependencies,
+    #[serde(deserialize_with = "crate::util::hash::deserialize_hash")]
     hash: String,
+    #[serde(default)]
     url: String,
+    /// The url of the detached signature of the package (default: `url` with `.sig` appended to its path)
+    #[serde(default)]
+    signature: String,
+    /// The urls of the same package on other mirrors, along with the urls of their signatures
+    #[serde(skip)]
+    fallback_urls: Vec<(String, String)>,
 }
 
 impl RemotePackage {
+    /// Returns the url of this package on the mirror it has been resolved from
+    pub fn get_url(&self) -> &str {
+        &self.url
+    }
+
+    /// Sets the url of this package on the mirror it has been resolved from
+    /// # Arguments
+    /// * `url` - The absolute url of the package
+    pub fn set_url(&mut self, url: &str) {
+        self.url = url.to_owned();
+    }
+
+    /// Returns the url of the detached signature of this package on the mirror it has been
+    /// resolved from, the package url with `.sig` appended to its path if the mirror lists none
+    pub fn get_signature_url(&self) -> String {
+        match self.signature.is_empty() {
+            true => util::signature::signature_url(&self.url),
+            false => self.signature.clone(),
+        }
+    }
+
+    /// Sets the url of the detached signature of this package on the mirror it has been resolved from
+    /// # Arguments
+    /// * `url` - The absolute url of the signature
+    pub fn set_signature_url(&mut self, url: &str) {
+        self.signature = url.to_owned();
+    }
+
+    /// Returns the urls this package can be fetched from along with the urls of their signatures:
+    /// The urls of the mirror it has been resolved from, followed by the urls of other mirrors
+    /// providing the same package
+    pub fn get_urls(&self) -> Vec<(String, String)> {
+        std::iter::once((self.url.clone(), self.get_signature_url()))
+            .chain(self.fallback_urls.iter().cloned())
+            .collect()
+    }
+
+    /// Adds a url to fall back to if fetching from the previous ones fails
+    /// # Arguments
+    /// * `url` - The url of the same package on another mirror
+    /// * `signature_url` - The url of its detached signature on that mirror
+    pub fn add_fallback_url(&mut self, url: &str, signature_url: &str) {
+        if !self.get_urls().iter().any(|(u, _)| u == url) {
+            self.fallback_urls
+                .push((url.to_owned(), signature_url.to_owned()));
+        }
+    }
+
     /// Uses the provided configuration to fetch this remote package to the local system
+    ///
+    /// The urls of this package are tried in order until one of them delivers the package
     /// # Arguments
     /// * `config` - The configuration to use for acquiring information about the fetch process
     pub fn fetch(&self, config: &Config) -> Result<PackageRef, LError> {
@@ -40,39 +100,327 @@ impl RemotePackage {
             .get_download_dir()
             .join(self.get_full_name() + ".lfpkg");
 
-        //Check if a file exists and if so, check if the hash matches and skip the download
-        if file_path.exists() && util::hash::hash_file(&file_path)? == self.hash {
-            usermsg!("Skipped fetching of package: {}", self.get_fq_name());
+        //Check if a file exists and if so, check if it is this package and skip the download
+        if file_path.exists() {
+            match LocalPackage::from_file_checked(self, &file_path) {
+                Ok(local) => {
+                    usermsg!("Skipped fetching of package: {}", self.get_fq_name());
 
-            let hash = self.hash.clone();
-            let local_package = Arc::new(RwLock::new(PackageVariant::Local(
-                LocalPackage::from_remote(self, &file_path, &hash),
-            )));
+                    if !util::signature::signature_path(&file_path).exists() {
+                        self.fetch_signature(config, &self.get_signature_url(), &file_path)?;
+                    }
 
-            return Ok(local_package);
+                    return Ok(Arc::new(RwLock::new(PackageVariant::Local(local))));
+                }
+                Err(e) if e.class == LErrorClass::HashMismatch => {
+                    debug!("Fetching package {} again: {}", self.get_fq_name(), e)
+                }
+                Err(e) => return Err(e),
+            }
         }
 
-        let mut file = std::fs::File::create(&file_path)?;
+        let urls = self.get_urls();
+        let mut res = Err(LError::new_class(LErrorClass::Unknown));
+        for (i, (url, signature_url)) in urls.iter().enumerate() {
+            res = self.fetch_from(config, url, &file_path).and_then(|hash| {
+                self.fetch_signature(config, signature_url, &file_path)?;
+                Ok(hash)
+            });
+            match &res {
+                Ok(_) => break,
+                Err(e) if e.class == LErrorClass::Abort => break,
+                Err(_) if i + 1 < urls.len() => {
+                    userwarn!(
+                        "Trying next mirror for package {} ({} of {})",
+                        self.get_fq_name(),
+                        i + 2,
+                        urls.len()
+                    );
+                }
+                Err(_) => {}
+            }
+        }
+        res?;
 
-        match download(
-            &self.url,
+        // The manifest inside the package has to describe the package the mirror lists
+        let local = LocalPackage::from_file_checked(self, &file_path)
+            .err_prepend(&format!("When checking package {}", self.get_fq_name()))?;
+
+        Ok(Arc::new(RwLock::new(PackageVariant::Local(local))))
+    }
+
+    /// Fetches this package from the supplied url to `file_path` and returns its hash
+    ///
+    /// The package gets downloaded to a partial file first, resuming a previous attempt,
+    /// or copied (reflinked if possible) from a local mirror.
+    /// It is only moved into place once its hash has been verified
+    /// # Arguments
+    /// * `config` - The configuration to use for acquiring information about the fetch process
+    /// * `url` - The url to fetch the package from
+    /// * `file_path` - The path to store the package at
+    fn fetch_from(&self, config: &Config, url: &str, file_path: &Path) -> Result<String, LError> {
+        //Local sources are copied or reflinked, remote ones downloaded into a partial file first
+        let part_path = part_path(file_path);
+        let res = match local_path(url) {
+            Some(source) => copy_local(&source, &part_path),
+            None => self.download_part(config, url, &part_path),
+        };
+
+        match res {
+            Ok(_) => usermsg!("Fetched package {}", self.get_fq_name()),
+            Err(e) => {
+                usererr!(
+                    "Failed to fetch package {} from {}: {}",
+                    self.get_fq_name(),
+                    url,
+                    e.clone().message.unwrap_or("".to_string())
+                );
+                //Keep what has been received to resume later, unless the mirror refused
+                if e.class == LErrorClass::CURLHttpNot2xx {
+                    remove_download(&part_path);
+                }
+                return Err(e);
+            }
+        };
+
+        // Make sure the mirror delivered what it promised before moving it into place
+        let (algorithm, _) = util::hash::split_hash(&self.hash)?;
+        let hash = util::hash::hash_file_with(&part_path, algorithm)?;
+        if hash != self.hash {
+            usererr!("Hash mismatch for package {}", self.get_fq_name());
+            remove_download(&part_path);
+            return Err(LError::new(
+                LErrorClass::HashMismatch,
+                &format!(
+                    "Package {} from {}: expected {}, got {}",
+                    self.get_fq_name(),
+                    url,
+                    self.hash,
+                    hash
+                ),
+            ));
+        }
+        std::fs::rename(&part_path, file_path)?;
+
+        Ok(hash)
+    }
+
+    /// Downloads this package from the supplied url to `part_path`,
+    /// resuming a previous attempt if the file exists
+    /// # Arguments
+    /// * `config` - The configuration to use for acquiring information about the fetch process
+    /// * `url` - The url to download the package from
+    /// * `part_path` - The path of the partial file
+    fn download_part(&self, config: &Config, url: &str, part_path: &Path) -> Result<(), LError> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(part_path)?;
+        let offset = file.seek(SeekFrom::End(0))?;
+        if offset > 0 {
+            usermsg!(
+                "Resuming fetch of package {} at {}",
+                self.get_fq_name(),
+                HumanBytes(offset)
+            );
+        }
+
+        download_from(
+            url,
             format!("Fetching package {}", self.get_fq_name()).as_str(),
             config.render_bar,
-            move |data| file.write_all(data).is_ok(),
+            offset,
+            config.get_retry_policy(),
+            move |pos, data| {
+                //The server may ignore the range and send the whole file
+                if pos == 0 && file.set_len(0).is_err() {
+                    return false;
+                }
+                file.seek(SeekFrom::Start(pos)).is_ok() && file.write_all(data).is_ok()
+            },
+        )?;
+
+        Ok(())
+    }
+
+    /// Fetches the detached signature of this package from `url` to `<file_path>.sig`
+    ///
+    /// A signature the mirror does not have (`404`, `410` or a missing local file) only gets logged,
+    /// deploying the package decides if it may be unsigned. Any other failure is an error, so it
+    /// can't pass for a missing signature
+    /// # Arguments
+    /// * `config` - The configuration to use for acquiring information about the fetch process
+    /// * `url` - The url of the signature
+    /// * `file_path` - The path of the downloaded package
+    fn fetch_signature(&self, config: &Config, url: &str, file_path: &Path) -> Result<(), LError> {
+        let sig_path = util::signature::signature_path(file_path);
+        let mut signature: Vec<u8> = Vec::new();
+
+        match download(
+            url,
+            format!("Fetching signature of {}", self.get_fq_name()).as_str(),
+            false,
+            config.get_retry_policy(),
+            |data| {
+                signature.extend_from_slice(data);
+                true
+            },
         ) {
-            Ok(_) => usermsg!("Fetched package {}", self.get_fq_name()),
-            Err(e) => usererr!(
-                "Failed to fetch package {}: {}",
-                self.get_fq_name(),
-                e.message.unwrap_or("".to_string())
-            ),
-        };
+            Ok(_) => {
+                let res = std::fs::write(&sig_path, &signature).err_prepend(&format!(
+                    "When writing signature of package {}",
+                    self.get_fq_name()
+                ));
+                if res.is_err() {
+                    remove_download(&sig_path);
+                }
+                res?;
+            }
+            Err(e) if is_not_found(&e) => {
+                warn!(
+                    "Package {} has no signature: {}",
+                    self.get_fq_name(),
+                    e.message.unwrap_or_default()
+                );
+                if sig_path.exists() {
+                    remove_download(&sig_path);
+                }
+            }
+            Err(e) => {
+                usererr!(
+                    "Failed to fetch signature of package {} from {}: {}",
+                    self.get_fq_name(),
+                    url,
+                    e
+                );
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the path a download to `path` is written to until it is complete: `<path>.part`
+/// # Arguments
+/// * `path` - The path of the downloaded file
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// Copies the package at `source` from a local mirror to `part_path`, reflinking it if possible
+///
+/// The package is never hard linked: The download directory must not share its files with the
+/// mirror, where they can be modified after they have been verified
+/// # Arguments
+/// * `source` - The package on the local mirror
+/// * `part_path` - The path to place the package at
+fn copy_local(source: &Path, part_path: &Path) -> Result<(), LError> {
+    if part_path.exists() {
+        std::fs::remove_file(part_path)?;
+    }
+
+    util::fs::copy_file(source, part_path).err_prepend(&format!(
+        "When fetching {} from local mirror",
+        source.to_string_lossy()
+    ))?;
+
+    Ok(())
+}
+
+/// Removes a failed or corrupt download, failing to do so only gets logged
+/// # Arguments
+/// * `path` - The path of the downloaded file
+fn remove_download(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        warn!(
+            "Failed to remove download {}: {}",
+            path.to_string_lossy(),
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn local_packages_are_not_hard_linked() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("test-1.0.lfpkg");
+        let part = dir.path().join("test-1.0.lfpkg.part");
+        std::fs::write(&source, b"package").unwrap();
+        std::fs::write(&part, b"stale").unwrap();
+
+        copy_local(&source, &part).unwrap();
+
+        assert_eq!(std::fs::read(&part).unwrap(), b"package");
+        let (source, part) = (source.metadata().unwrap(), part.metadata().unwrap());
+        assert_ne!(source.ino(), part.ino());
+        assert_eq!(source.nlink(), 1);
+    }
+
+    fn package() -> RemotePackage {
+        serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "version": "1.0",
+            "real_version": 1,
+            "description": "",
+            "dependencies": [],
+            "hash": "sha256:0000",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn signature_urls_default_to_the_package_path() {
+        let mut package = package();
+        package.set_url("https://mirror.example.org/get?name=test");
+        assert_eq!(
+            package.get_signature_url(),
+            "https://mirror.example.org/get.sig?name=test"
+        );
+
+        package.set_signature_url("https://mirror.example.org/sig?name=test");
+        assert_eq!(
+            package.get_urls(),
+            vec![(
+                "https://mirror.example.org/get?name=test".to_owned(),
+                "https://mirror.example.org/sig?name=test".to_owned()
+            )]
+        );
+    }
+
+    #[test]
+    fn only_missing_signatures_are_tolerated() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::default();
+        let package = package();
+        let file = dir.path().join("test-1.0.lfpkg");
+        let sig_path = util::signature::signature_path(&file);
+        let url = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+
+        std::fs::write(dir.path().join("mirror.sig"), b"signature").unwrap();
+        package
+            .fetch_signature(&config, &url("mirror.sig"), &file)
+            .unwrap();
+        assert_eq!(std::fs::read(&sig_path).unwrap(), b"signature");
 
-        let hash = util::hash::hash_file(&file_path).expect("Hash");
-        let local_package = Arc::new(RwLock::new(PackageVariant::Local(
-            LocalPackage::from_remote(self, &file_path, &hash),
-        )));
+        // A signature the mirror does not have removes the stale one
+        package
+            .fetch_signature(&config, &url("missing.sig"), &file)
+            .unwrap();
+        assert!(!sig_path.exists());
 
-        Ok(local_package)
+        // Any other failure does not pass for a missing signature
+        std::fs::create_dir(dir.path().join("broken.sig")).unwrap();
+        assert!(package
+            .fetch_signature(&config, &url("broken.sig"), &file)
+            .is_err());
     }
 }
@@ -3,17 +3,21 @@
 use crate::package::*;
 use crate::{config::Config, error::*};
 use serde::{Deserialize, Deserializer};
-use std::fs::File;
-use std::path::{Path, PathBuf};
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use std::{fmt::Display, fs::create_dir_all, str::FromStr};
 use tar::Archive;
-use xz::read::XzDecoder;
 
+pub mod compression;
 pub mod dependencies;
+pub mod deploy;
 pub mod fs;
 pub mod hash;
+pub mod scripts;
+pub mod signature;
 pub mod transaction;
+pub mod triggers;
 
 fn ensure_dir(dir: &PathBuf) -> Result<(), LError> {
     if !dir.exists() {
@@ -27,6 +31,8 @@ fn ensure_dir(dir: &PathBuf) -> Result<(), LError> {
 pub fn ensure_dirs(conf: &Config) -> Result<(), LError> {
     //Ensures /etc/leaf and /etc/leaf/mirrors
     ensure_dir(&conf.get_mirrors_dir())?;
+    //Ensures /etc/leaf/keyring
+    ensure_dir(&conf.get_keyring_dir())?;
 
     //Ensures /var/cache/leaf/ and /var/cache/leaf/download
     ensure_dir(&conf.get_download_dir())?;
@@ -78,21 +84,143 @@ where
     }
 }
 
-/// A convenient wrapper around the tar and xz libararies
+/// Returns `true` if the current process runs with an effective user id of `0`
+pub fn running_as_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// A convenient wrapper around the tar and compression libraries,
+/// the compression gets detected from the magic bytes of the source
+///
+/// Permissions and modification times are preserved, ownership only if running as root.
+/// Every entry gets validated before it is extracted, the archive is rejected with
+/// `LErrorClass::UnsafeArchive` if an entry would end up outside of `destination/root`:
+/// - Absolute paths and paths containing `..`
+/// - Entries outside of `root`
+/// - Hard links and relative symlinks pointing outside of `root`
+/// - Entries that would be written through a symlink of the archive
+/// - Hard links pointing through a symlink of the archive
 /// # Arguments
-/// * `source` - The path to the source tarball
+/// * `source` - The opened source tarball, read from its start
 /// * `destination` - The destination path to extract into
-pub fn extract(source: &Path, destination: &Path) -> Result<(), LError> {
-    let tar_file = File::open(source)?;
-    let tar = XzDecoder::new(tar_file);
-
-    let mut archive = Archive::new(tar);
+/// * `root` - The directory inside the archive all entries have to live in (e.g. `glibc-2.36`)
+pub fn extract(source: std::fs::File, destination: &Path, root: &Path) -> Result<(), LError> {
+    let mut archive = Archive::new(compression::open_file(source)?);
     archive.set_overwrite(true);
-    archive.unpack(destination)?;
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_preserve_ownerships(running_as_root());
+
+    let unsafe_entry = |path: &Path, reason: &str| {
+        Err(LError::new(
+            LErrorClass::UnsafeArchive,
+            &format!("Entry {} {}", path.to_string_lossy(), reason),
+        ))
+    };
+
+    let mut symlinks: HashSet<PathBuf> = HashSet::new();
+    let mut directories = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = archive_path(&entry.path()?)?;
+
+        if !path.starts_with(root) {
+            return unsafe_entry(&path, &format!("is outside of {}", root.to_string_lossy()));
+        }
+        if path.ancestors().skip(1).any(|p| symlinks.contains(p)) {
+            return unsafe_entry(&path, "would be written through a symlink");
+        }
+
+        match entry.header().entry_type() {
+            tar::EntryType::Symlink => {
+                let target = entry.link_name()?.unwrap_or_default().to_path_buf();
+                if target.is_relative() {
+                    match resolve_link(path.parent().unwrap_or(Path::new("")), &target) {
+                        Some(t) if t.starts_with(root) => {}
+                        _ => return unsafe_entry(&path, "is a symlink pointing outside"),
+                    }
+                }
+                symlinks.insert(path.clone());
+            }
+            tar::EntryType::Link => {
+                let target = archive_path(&entry.link_name()?.unwrap_or_default())?;
+                if !target.starts_with(root) {
+                    return unsafe_entry(&path, "is a hard link pointing outside");
+                }
+                if target.ancestors().skip(1).any(|p| symlinks.contains(p)) {
+                    return unsafe_entry(&path, "is a hard link pointing through a symlink");
+                }
+            }
+            _ => {}
+        }
+
+        // Directories are unpacked last to keep their mtime
+        if entry.header().entry_type() == tar::EntryType::Directory {
+            directories.push(entry);
+            continue;
+        }
+        entry.unpack_in(destination)?;
+    }
+
+    for mut dir in directories {
+        dir.unpack_in(destination)?;
+    }
 
     Ok(())
 }
 
+/// Normalizes a path found in an archive by removing all `.` components
+///
+/// Returns `LErrorClass::UnsafeArchive` if the path is absolute or contains `..`
+/// # Arguments
+/// * `path` - The path of the archive entry
+pub(crate) fn archive_path(path: &Path) -> Result<PathBuf, LError> {
+    let mut res = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => res.push(c),
+            Component::CurDir => {}
+            _ => {
+                return Err(LError::new(
+                    LErrorClass::UnsafeArchive,
+                    &format!(
+                        "Archive path {} is absolute or contains '..'",
+                        path.to_string_lossy()
+                    ),
+                ))
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+/// Lexically resolves the relative symlink `target` placed in `dir`
+///
+/// Returns `None` if the target leaves the top of `dir`
+/// # Arguments
+/// * `dir` - The directory containing the symlink
+/// * `target` - The relative target of the symlink
+fn resolve_link(dir: &Path, target: &Path) -> Option<PathBuf> {
+    let mut res = dir.to_path_buf();
+
+    for component in target.components() {
+        match component {
+            Component::Normal(c) => res.push(c),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !res.pop() {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    Some(res)
+}
+
 impl From<toml::de::Error> for LError {
     fn from(value: toml::de::Error) -> Self {
         LError {
@@ -101,3 +229,112 @@ impl From<toml::de::Error> for LError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tar::{Builder, EntryType, Header};
+
+    /// Writes an uncompressed archive containing `pkg/data/etc` as a symlink to `target`,
+    /// followed by the supplied link entry
+    fn archive(path: &Path, target: &Path, entry_type: EntryType, name: &str, link: &str) {
+        let mut builder = Builder::new(std::fs::File::create(path).unwrap());
+        for (entry_type, name, link) in [
+            (EntryType::Symlink, "pkg/data/etc", target.to_str().unwrap()),
+            (entry_type, name, link),
+        ] {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(0o777);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_size(0);
+            builder.append_link(&mut header, name, link).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn archive_path_normalizes_relative_paths() {
+        assert_eq!(
+            archive_path(Path::new("./pkg/./data/file")).unwrap(),
+            PathBuf::from("pkg/data/file")
+        );
+        assert_eq!(archive_path(Path::new(".")).unwrap(), PathBuf::new());
+    }
+
+    #[test]
+    fn archive_path_refuses_escaping_paths() {
+        for path in ["/etc/shadow", "pkg/../../etc", "..", "pkg/data/.."] {
+            let err = archive_path(Path::new(path)).unwrap_err();
+            assert_eq!(err.class, LErrorClass::UnsafeArchive, "{}", path);
+        }
+    }
+
+    #[test]
+    fn resolve_link_stays_inside_the_directory() {
+        assert_eq!(
+            resolve_link(Path::new("pkg/data/bin"), Path::new("../lib/./libc.so")),
+            Some(PathBuf::from("pkg/data/lib/libc.so"))
+        );
+        assert_eq!(
+            resolve_link(Path::new("pkg/data"), Path::new("../../pkg")),
+            Some(PathBuf::from("pkg"))
+        );
+    }
+
+    #[test]
+    fn resolve_link_refuses_escaping_targets() {
+        assert_eq!(
+            resolve_link(Path::new("pkg/data"), Path::new("../../../etc")),
+            None
+        );
+        assert_eq!(resolve_link(Path::new("pkg"), Path::new("/etc")), None);
+    }
+
+    #[test]
+    fn extract_refuses_symlinks_pointing_outside() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("pkg.tar");
+        let dest = dir.path().join("packages");
+        std::fs::create_dir(&dest).unwrap();
+        archive(
+            &source,
+            Path::new("shadow"),
+            EntryType::Symlink,
+            "pkg/data/passwd",
+            "../../../etc/passwd",
+        );
+
+        let file = std::fs::File::open(&source).unwrap();
+        let err = extract(file, &dest, Path::new("pkg")).unwrap_err();
+
+        assert_eq!(err.class, LErrorClass::UnsafeArchive);
+        assert!(!dest.join("pkg/data/passwd").is_symlink());
+    }
+
+    #[test]
+    fn extract_refuses_hard_links_through_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("pkg.tar");
+        let dest = dir.path().join("packages");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir(&dest).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::write(outside.join("shadow"), b"secret").unwrap();
+        archive(
+            &source,
+            &outside,
+            EntryType::Link,
+            "pkg/data/shadow",
+            "pkg/data/etc/shadow",
+        );
+
+        let file = std::fs::File::open(&source).unwrap();
+        let err = extract(file, &dest, Path::new("pkg")).unwrap_err();
+
+        assert_eq!(err.class, LErrorClass::UnsafeArchive);
+        assert!(!dest.join("pkg/data/shadow").exists());
+    }
+}
@@ -0,0 +1,258 @@
+//! This module provides detection and handling of the compressions packages and package lists can use
+use crate::error::*;
+use flate2::{read::GzDecoder, write::GzEncoder};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use xz::{read::XzDecoder, write::XzEncoder};
+
+/// The compressions a package archive can use
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Compression {
+    /// A plain tar archive
+    None,
+    Gzip,
+    #[default]
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// Detects the compression of the supplied data by its magic bytes,
+    /// data without a known magic is assumed to be uncompressed
+    /// # Arguments
+    /// * `magic` - The first bytes of the data (at least 6 to detect all compressions)
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Self::Xz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd
+        } else if magic.starts_with(&[0x1f, 0x8b]) {
+            Self::Gzip
+        } else {
+            Self::None
+        }
+    }
+
+    /// Returns the compression the supplied MIME content type stands for,
+    /// `None` if it is not a known compressed type
+    /// # Arguments
+    /// * `content_type` - The content type, parameters like `; charset=...` are ignored
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        match mime.to_ascii_lowercase().as_str() {
+            "application/gzip" | "application/x-gzip" => Some(Self::Gzip),
+            "application/x-xz" | "application/xz" => Some(Self::Xz),
+            "application/zstd" | "application/x-zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Decompresses the supplied data, refusing to produce more than `limit` bytes
+    /// # Arguments
+    /// * `data` - The compressed data
+    /// * `limit` - The maximum size of the decompressed data in bytes
+    pub fn decompress(&self, data: Vec<u8>, limit: u64) -> Result<Vec<u8>, LError> {
+        let res = if *self == Self::None {
+            data
+        } else {
+            // Read one byte past the limit to tell a full buffer from an overflowing one
+            let mut res = Vec::new();
+            self.decoder(data.as_slice())?
+                .take(limit.saturating_add(1))
+                .read_to_end(&mut res)?;
+            res
+        };
+
+        if res.len() as u64 > limit {
+            return Err(LError::new(
+                LErrorClass::IO(std::io::ErrorKind::InvalidData),
+                &format!("Decompressed data exceeds the limit of {} bytes", limit),
+            ));
+        }
+        Ok(res)
+    }
+
+    /// Wraps the supplied reader into a decoder for this compression
+    /// # Arguments
+    /// * `reader` - The reader providing the compressed data
+    pub fn decoder<'a, R: Read + 'a>(&self, reader: R) -> Result<Box<dyn Read + 'a>, LError> {
+        Ok(match self {
+            Self::None => Box::new(reader),
+            Self::Gzip => Box::new(GzDecoder::new(reader)),
+            Self::Xz => Box::new(XzDecoder::new(reader)),
+            Self::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        })
+    }
+
+    /// Wraps the supplied writer into an encoder for this compression
+    /// # Arguments
+    /// * `writer` - The writer to write the compressed data to
+    pub fn encoder<W: Write>(&self, writer: W) -> Result<Encoder<W>, LError> {
+        Ok(match self {
+            Self::None => Encoder::None(writer),
+            Self::Gzip => Encoder::Gzip(GzEncoder::new(writer, flate2::Compression::best())),
+            Self::Xz => Encoder::Xz(XzEncoder::new(writer, 9)),
+            Self::Zstd => Encoder::Zstd(zstd::Encoder::new(writer, 19)?),
+        })
+    }
+}
+
+/// An encoder for one of the supported compressions
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Xz(XzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Finishes the compressed stream and returns the underlying writer
+    pub fn finish(self) -> Result<W, LError> {
+        Ok(match self {
+            Self::None(w) => w,
+            Self::Gzip(e) => e.finish()?,
+            Self::Xz(e) => e.finish()?,
+            Self::Zstd(e) => e.finish()?,
+        })
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::None(w) => w.write(buf),
+            Self::Gzip(e) => e.write(buf),
+            Self::Xz(e) => e.write(buf),
+            Self::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::None(w) => w.flush(),
+            Self::Gzip(e) => e.flush(),
+            Self::Xz(e) => e.flush(),
+            Self::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+/// Opens the file at `source` and returns a reader decompressing it
+/// using the compression detected from its magic bytes
+/// # Arguments
+/// * `source` - The path to the compressed file
+pub fn open(source: &Path) -> Result<Box<dyn Read>, LError> {
+    open_file(File::open(source)?)
+}
+
+/// Returns a reader decompressing the supplied file from its start,
+/// using the compression detected from its magic bytes
+/// # Arguments
+/// * `file` - The compressed file
+pub fn open_file(mut file: File) -> Result<Box<dyn Read>, LError> {
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut magic = [0u8; 6];
+    let mut len = 0;
+    while len < magic.len() {
+        match file.read(&mut magic[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    file.seek(SeekFrom::Start(0))?;
+
+    let compression = Compression::detect(&magic[..len]);
+    trace!("Detected compression {:?}", compression);
+
+    compression.decoder(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_recognizes_the_magic_bytes() {
+        assert_eq!(
+            Compression::detect(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]),
+            Compression::Xz
+        );
+        assert_eq!(
+            Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x00]),
+            Compression::Zstd
+        );
+        assert_eq!(Compression::detect(&[0x1f, 0x8b, 0x08]), Compression::Gzip);
+    }
+
+    #[test]
+    fn detect_falls_back_to_none() {
+        assert_eq!(Compression::detect(b"ustar\0"), Compression::None);
+        assert_eq!(Compression::detect(&[]), Compression::None);
+        // A truncated magic is not enough
+        assert_eq!(Compression::detect(&[0xfd, b'7', b'z']), Compression::None);
+    }
+
+    #[test]
+    fn from_content_type_ignores_parameters_and_case() {
+        assert_eq!(
+            Compression::from_content_type("application/x-xz"),
+            Some(Compression::Xz)
+        );
+        assert_eq!(
+            Compression::from_content_type("Application/GZIP; charset=binary"),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(
+            Compression::from_content_type(" application/zstd "),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(Compression::from_content_type("application/json"), None);
+        assert_eq!(Compression::from_content_type(""), None);
+    }
+
+    fn compress(compression: Compression, data: &[u8]) -> Vec<u8> {
+        let mut encoder = compression.encoder(Vec::new()).unwrap();
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn compressions_round_trip() {
+        let data = b"{\"status\": \"ok\"}".repeat(100);
+        for compression in [
+            Compression::None,
+            Compression::Gzip,
+            Compression::Xz,
+            Compression::Zstd,
+        ] {
+            let compressed = compress(compression, &data);
+            assert_eq!(Compression::detect(&compressed), compression);
+            let res = compression.decompress(compressed, data.len() as u64);
+            assert_eq!(res.unwrap(), data, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn decompression_is_capped() {
+        let data = vec![0u8; 1024 * 1024];
+        for compression in [
+            Compression::None,
+            Compression::Gzip,
+            Compression::Xz,
+            Compression::Zstd,
+        ] {
+            let compressed = compress(compression, &data);
+            let e = compression
+                .decompress(compressed, data.len() as u64 - 1)
+                .unwrap_err();
+            assert_eq!(
+                e.class,
+                LErrorClass::IO(std::io::ErrorKind::InvalidData),
+                "{:?}",
+                compression
+            );
+        }
+    }
+}
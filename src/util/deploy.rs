@@ -0,0 +1,577 @@
+//! This module provides deployment of packages directly from their archive
+//! without extracting them into the packages directory first
+use crate::error::{LError, LErrorClass, LErrorExt};
+use crate::util::{
+    self,
+    fs::{DeployOptions, FSEntry, FSEntryType},
+    triggers::PendingTriggers,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tar::{Archive, EntryType};
+
+/// The scripts of a package by their path relative to `scripts/` (e.g. `postinstall`)
+pub type Scripts = HashMap<String, Vec<u8>>;
+
+/// Deploys the `data/` directory of the package directory `package_dir` in the archive at
+/// `source` into `dest_root` and returns the deployed tree and the scripts of the package
+///
+/// The archive is read only once: Every entry gets hashed while it is streamed to a
+/// temporary name next to its destination and is then renamed into place.
+/// If `options.fsync` is set, files are synced before and their directories after renaming.
+/// The returned tree equals the one `util::fs::index()` would produce for the extracted `data/`.
+///
+/// The files in `scripts/` are collected on the way and passed to `before_deploy`, which gets
+/// called right before the first entry is deployed. So they have to precede `data/` in the archive.
+///
+/// Entries that would be written through a symlink of the archive and hard links pointing
+/// through one are rejected with `LErrorClass::UnsafeArchive`.
+/// # Arguments
+/// * `source` - The opened source tarball, compressed using any of `util::compression::Compression`
+/// * `package_dir` - The directory inside the archive containing `data/` and `scripts/` (e.g. `glibc-2.36`)
+/// * `dest_root` - The root directory to deploy into
+/// * `options` - The options for placing the entries
+/// * `triggers` - The pending triggers to activate for every deployed entry
+/// * `file_exists_handler` - A handler that gets called if the entry does already exist, true indicates overwrite
+/// * `before_deploy` - A handler that gets called with the scripts before deploying the first entry
+pub fn deploy_archive<F, B>(
+    source: File,
+    package_dir: &Path,
+    dest_root: &Path,
+    options: &DeployOptions,
+    triggers: &mut PendingTriggers,
+    file_exists_handler: &F,
+    before_deploy: B,
+) -> Result<(Vec<FSEntry>, Scripts), LError>
+where
+    F: Fn(&Path) -> bool,
+    B: FnOnce(&Scripts) -> Result<(), LError>,
+{
+    let mut archive = Archive::new(util::compression::open_file(source)?);
+    let prefix = package_dir.join("data");
+    let scripts_dir = package_dir.join("scripts");
+
+    let mut scripts = Scripts::new();
+    let mut before_deploy = Some(before_deploy);
+
+    let mut files: Vec<FSEntry> = Vec::new();
+    let mut hashes: HashMap<PathBuf, Option<String>> = HashMap::new();
+    let mut created_dirs: Vec<(PathBuf, FSEntry)> = Vec::new();
+    let mut renamed_dirs: HashSet<PathBuf> = HashSet::new();
+    let mut symlinks: HashSet<PathBuf> = HashSet::new();
+
+    let unsafe_entry = |path: &Path, reason: &str| {
+        Err(LError::new(
+            LErrorClass::UnsafeArchive,
+            &format!("Entry {} {}", path.to_string_lossy(), reason),
+        ))
+    };
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        let path = util::archive_path(&entry.path()?)?;
+
+        if let Ok(script) = path.strip_prefix(&scripts_dir) {
+            if !entry.header().entry_type().is_file() || script.as_os_str().is_empty() {
+                continue;
+            }
+            if before_deploy.is_none() {
+                return Err(LError::new(
+                    LErrorClass::IO(std::io::ErrorKind::InvalidData),
+                    &format!(
+                        "Script {} has to precede the data in the archive",
+                        path.to_string_lossy()
+                    ),
+                ));
+            }
+
+            trace!("Reading script {}", path.to_string_lossy());
+            let mut data: Vec<u8> = Vec::new();
+            entry.read_to_end(&mut data)?;
+            scripts.insert(script.to_string_lossy().to_string(), data);
+            continue;
+        }
+
+        let relative = match path.strip_prefix(&prefix) {
+            Ok(r) if r.components().next().is_some() => r.to_path_buf(),
+            _ => {
+                trace!("Skipping archive entry {}", path.to_string_lossy());
+                continue;
+            }
+        };
+        let dest = dest_root.join(&relative);
+
+        if relative.ancestors().skip(1).any(|p| symlinks.contains(p)) {
+            return unsafe_entry(&path, "would be written through a symlink");
+        }
+
+        if let Some(before_deploy) = before_deploy.take() {
+            before_deploy(&scripts)?;
+        }
+
+        let header = entry.header();
+        let entry_type = match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous => FSEntryType::File,
+            EntryType::Directory => FSEntryType::Directory,
+            EntryType::Symlink => FSEntryType::Symlink,
+            EntryType::Link => FSEntryType::HardLink,
+            EntryType::Char => FSEntryType::CharDevice,
+            EntryType::Block => FSEntryType::BlockDevice,
+            EntryType::Fifo => FSEntryType::FIFO,
+            other => {
+                warn!(
+                    "Skipping unsupported archive entry {} of type {:?}",
+                    path.to_string_lossy(),
+                    other
+                );
+                continue;
+            }
+        };
+
+        let rdev = match entry_type {
+            FSEntryType::CharDevice | FSEntryType::BlockDevice => {
+                match (header.device_major()?, header.device_minor()?) {
+                    (Some(major), Some(minor)) => libc::makedev(major, minor),
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        };
+
+        let mut new_entry = FSEntry {
+            name: relative
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            entry_type,
+            hash: None,
+            children: Vec::new(),
+            mode: header.mode()? & 0o7777,
+            uid: header.uid()? as u32,
+            gid: header.gid()? as u32,
+            mtime: header.mtime()? as i64,
+            link_target: None,
+            rdev,
+        };
+
+        if entry_type == FSEntryType::Directory {
+            if !dest.exists() {
+                trace!("Creating directory {}", dest.to_string_lossy());
+                std::fs::create_dir_all(&dest).err_append(&format!(
+                    "When creating directory {}",
+                    dest.to_string_lossy()
+                ))?;
+                created_dirs.push((dest.clone(), new_entry.clone()));
+            }
+            insert_entry(&mut files, &relative, new_entry);
+            continue;
+        }
+
+        // If the destination exists, call the callback
+        if dest.is_symlink() || dest.exists() {
+            if !file_exists_handler(&dest) {
+                return Err(LError::new(
+                    LErrorClass::IO(std::io::ErrorKind::AlreadyExists),
+                    &dest.to_string_lossy(),
+                ));
+            }
+            warn!("Overwriting destination at {:?}", dest);
+        }
+
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // The temporary entry gets removed again if deploying it fails
+        let temp = util::fs::TempFile::new(&dest);
+        if temp.path().is_symlink() || temp.path().exists() {
+            std::fs::remove_file(temp.path()).err_prepend("When removing stale temporary file")?;
+        }
+
+        let msg = format!("Deploying {} to {}", entry_type, dest.to_string_lossy());
+        trace!("{}", &msg);
+        match entry_type {
+            FSEntryType::File => {
+                let mut file = File::create(temp.path()).err_append(&msg)?;
+                new_entry.hash =
+                    Some(util::hash::hash_copy(&mut entry, &mut file).err_append(&msg)?);
+                new_entry.apply_metadata(temp.path())?;
+                if options.fsync {
+                    file.sync_all().err_append(&msg)?;
+                }
+            }
+            FSEntryType::Symlink => {
+                let target = link_name(&entry)?;
+                new_entry.hash = Some(util::hash::hash_str(&target.to_string_lossy()));
+                std::os::unix::fs::symlink(&target, temp.path()).err_append(&msg)?;
+                new_entry.apply_metadata(temp.path())?;
+                symlinks.insert(relative.clone());
+            }
+            FSEntryType::HardLink => {
+                let target = util::archive_path(&link_name(&entry)?)?;
+                let target = match target.strip_prefix(&prefix) {
+                    Ok(t) => t.to_path_buf(),
+                    Err(_) => {
+                        return Err(LError::new(
+                            LErrorClass::IO(std::io::ErrorKind::InvalidData),
+                            &format!(
+                                "Hard link {} points outside of the package: {}",
+                                path.to_string_lossy(),
+                                target.to_string_lossy()
+                            ),
+                        ))
+                    }
+                };
+                if target.ancestors().skip(1).any(|p| symlinks.contains(p)) {
+                    return unsafe_entry(&path, "is a hard link pointing through a symlink");
+                }
+                new_entry.hash = hashes.get(&target).cloned().flatten();
+                new_entry.link_target = Some(target.to_string_lossy().to_string());
+                std::fs::hard_link(dest_root.join(&target), temp.path()).err_append(&msg)?;
+            }
+            _ => {
+                util::fs::mknod(temp.path(), &new_entry).err_append(&msg)?;
+                new_entry.apply_metadata(temp.path())?;
+            }
+        }
+
+        temp.persist(&dest).err_append(&msg)?;
+        if let Some(parent) = dest.parent() {
+            renamed_dirs.insert(parent.to_path_buf());
+        }
+        triggers.collect(&relative);
+
+        hashes.insert(relative.clone(), new_entry.hash.clone());
+        insert_entry(&mut files, &relative, new_entry);
+    }
+
+    // A package without data still runs its scripts
+    if let Some(before_deploy) = before_deploy.take() {
+        before_deploy(&scripts)?;
+    }
+
+    // Apply the metadata of the directories last, deploying their children changes the mtime.
+    // Existing directories are shared with the system and other packages, they are left alone
+    for (path, entry) in created_dirs.iter().rev() {
+        entry.apply_metadata(path)?;
+    }
+
+    // Make sure the renames hit the disk
+    if options.fsync {
+        for dir in renamed_dirs {
+            util::fs::sync_path(&dir)
+                .err_append(&format!("When syncing directory {}", dir.to_string_lossy()))?;
+        }
+    }
+
+    Ok((files, scripts))
+}
+
+/// Returns the link name of the supplied archive entry
+/// # Arguments
+/// * `entry` - The entry to get the link name of
+fn link_name<R: Read>(entry: &tar::Entry<R>) -> Result<PathBuf, LError> {
+    match entry.link_name()? {
+        Some(l) => Ok(l.to_path_buf()),
+        None => Err(LError::new(
+            LErrorClass::IO(std::io::ErrorKind::InvalidData),
+            &format!(
+                "Archive entry {} is missing its link name",
+                entry.path()?.to_string_lossy()
+            ),
+        )),
+    }
+}
+
+/// Inserts the supplied entry into the tree at the supplied relative path,
+/// creating missing parent directories on the way
+///
+/// If an entry with the same name already exists, it gets replaced, keeping its children
+/// # Arguments
+/// * `tree` - The tree to insert into
+/// * `path` - The path of the new entry relative to the tree
+/// * `entry` - The entry to insert
+fn insert_entry(tree: &mut Vec<FSEntry>, path: &Path, mut entry: FSEntry) {
+    let mut components = path.components().peekable();
+    let mut cur = tree;
+
+    while let Some(component) = components.next() {
+        let name = component.as_os_str().to_string_lossy().to_string();
+        let pos = cur.iter().position(|e| e.name == name);
+
+        // The last component is the entry itself
+        if components.peek().is_none() {
+            match pos {
+                Some(pos) => {
+                    entry.children = std::mem::take(&mut cur[pos].children);
+                    cur[pos] = entry;
+                }
+                None => cur.push(entry),
+            }
+            return;
+        }
+
+        let pos = match pos {
+            Some(pos) => pos,
+            None => {
+                cur.push(FSEntry {
+                    name,
+                    entry_type: FSEntryType::Directory,
+                    hash: None,
+                    children: Vec::new(),
+                    mode: 0o755,
+                    uid: 0,
+                    gid: 0,
+                    mtime: 0,
+                    link_target: None,
+                    rdev: 0,
+                });
+                cur.len() - 1
+            }
+        };
+        cur = &mut cur[pos].children;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::Permissions;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use tar::{Builder, Header};
+
+    /// The type and contents of an archive entry for testing
+    enum Test<'a> {
+        Dir,
+        File(&'a [u8]),
+        Symlink(&'a str),
+        HardLink(&'a str),
+    }
+
+    /// Writes an uncompressed archive containing the supplied entries
+    fn archive(path: &Path, entries: &[(&str, Test)]) {
+        let mut builder = Builder::new(File::create(path).unwrap());
+        for (name, entry) in entries {
+            let mut header = Header::new_gnu();
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            match entry {
+                Test::Dir => {
+                    header.set_entry_type(EntryType::Directory);
+                    header.set_mode(0o755);
+                    header.set_size(0);
+                    builder
+                        .append_data(&mut header, name, std::io::empty())
+                        .unwrap();
+                }
+                Test::File(data) => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_mode(0o644);
+                    header.set_size(data.len() as u64);
+                    builder.append_data(&mut header, name, *data).unwrap();
+                }
+                Test::Symlink(target) | Test::HardLink(target) => {
+                    header.set_entry_type(match entry {
+                        Test::Symlink(_) => EntryType::Symlink,
+                        _ => EntryType::Link,
+                    });
+                    header.set_mode(0o777);
+                    header.set_size(0);
+                    builder.append_link(&mut header, name, target).unwrap();
+                }
+            }
+        }
+        builder.finish().unwrap();
+    }
+
+    /// Deploys the `pkg` package directory of the archive at `source` into `dest`
+    fn deploy<B>(source: &Path, dest: &Path, before_deploy: B) -> Result<Scripts, LError>
+    where
+        B: FnOnce(&Scripts) -> Result<(), LError>,
+    {
+        let options = DeployOptions {
+            fsync: false,
+            ..Default::default()
+        };
+        deploy_archive(
+            File::open(source).unwrap(),
+            Path::new("pkg"),
+            dest,
+            &options,
+            &mut PendingTriggers::default(),
+            &|_| false,
+            before_deploy,
+        )
+        .map(|(_, scripts)| scripts)
+    }
+
+    #[test]
+    fn scripts_are_collected_before_deploying() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("pkg.lfpkg");
+        let dest = dir.path().join("root");
+        std::fs::create_dir(&dest).unwrap();
+        archive(
+            &source,
+            &[
+                ("pkg", Test::Dir),
+                ("pkg/scripts", Test::Dir),
+                ("pkg/scripts/preinstall", Test::File(b"pre")),
+                ("pkg/scripts/postinstall", Test::File(b"post")),
+                ("pkg/data", Test::Dir),
+                ("pkg/data/file", Test::File(b"data")),
+            ],
+        );
+
+        let scripts = deploy(&source, &dest, |scripts| {
+            assert_eq!(scripts.get("preinstall").unwrap(), b"pre");
+            assert!(!dest.join("file").exists());
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(scripts.get("postinstall").unwrap(), b"post");
+        assert_eq!(std::fs::read(dest.join("file")).unwrap(), b"data");
+    }
+
+    #[test]
+    fn scripts_after_data_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("pkg.lfpkg");
+        let dest = dir.path().join("root");
+        std::fs::create_dir(&dest).unwrap();
+        archive(
+            &source,
+            &[
+                ("pkg/data/file", Test::File(b"data")),
+                ("pkg/scripts/preinstall", Test::File(b"pre")),
+            ],
+        );
+
+        assert!(deploy(&source, &dest, |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn failing_before_deploy_deploys_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("pkg.lfpkg");
+        let dest = dir.path().join("root");
+        std::fs::create_dir(&dest).unwrap();
+        archive(&source, &[("pkg/data/file", Test::File(b"data"))]);
+
+        let res = deploy(&source, &dest, |_| {
+            Err(LError::new(LErrorClass::ScriptFailed, "preinstall"))
+        });
+
+        assert!(res.is_err());
+        assert!(!dest.join("file").exists());
+    }
+
+    #[test]
+    fn entries_through_symlinks_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("pkg.lfpkg");
+        let dest = dir.path().join("root");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir(&dest).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        archive(
+            &source,
+            &[
+                ("pkg/data/etc", Test::Symlink(outside.to_str().unwrap())),
+                ("pkg/data/etc/shadow", Test::File(b"data")),
+            ],
+        );
+
+        let err = deploy(&source, &dest, |_| Ok(())).unwrap_err();
+
+        assert_eq!(err.class, LErrorClass::UnsafeArchive);
+        assert!(!outside.join("shadow").exists());
+    }
+
+    #[test]
+    fn hard_links_through_symlinks_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("pkg.lfpkg");
+        let dest = dir.path().join("root");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir(&dest).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::write(outside.join("shadow"), b"secret").unwrap();
+        archive(
+            &source,
+            &[
+                ("pkg/data/etc", Test::Symlink(outside.to_str().unwrap())),
+                ("pkg/data/shadow", Test::HardLink("pkg/data/etc/shadow")),
+            ],
+        );
+
+        let err = deploy(&source, &dest, |_| Ok(())).unwrap_err();
+
+        assert_eq!(err.class, LErrorClass::UnsafeArchive);
+        assert!(!dest.join("shadow").exists());
+    }
+
+    #[test]
+    fn existing_directories_keep_their_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("pkg.tar");
+        let dest = dir.path().join("root");
+        archive(
+            &source,
+            &[
+                ("pkg/data/usr", Test::Dir),
+                ("pkg/data/usr/file", Test::File(b"data")),
+                ("pkg/data/opt", Test::Dir),
+            ],
+        );
+        std::fs::create_dir_all(dest.join("usr")).unwrap();
+        std::fs::set_permissions(dest.join("usr"), Permissions::from_mode(0o750)).unwrap();
+
+        deploy(&source, &dest, |_| Ok(())).unwrap();
+
+        let usr = std::fs::metadata(dest.join("usr")).unwrap();
+        assert_eq!(usr.mode() & 0o7777, 0o750);
+        assert_ne!(usr.mtime(), 0);
+        let opt = std::fs::metadata(dest.join("opt")).unwrap();
+        assert_eq!(opt.mode() & 0o7777, 0o755);
+        assert_eq!(opt.mtime(), 0);
+    }
+
+    #[test]
+    fn failed_files_leave_no_temporary_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("pkg.tar");
+        let dest = dir.path().join("root");
+        archive(
+            &source,
+            &[
+                ("pkg/data", Test::Dir),
+                ("pkg/data/file", Test::File(b"data")),
+            ],
+        );
+        // A directory that is not empty can't be replaced by the file
+        std::fs::create_dir_all(dest.join("file/child")).unwrap();
+
+        let res = deploy_archive(
+            File::open(&source).unwrap(),
+            Path::new("pkg"),
+            &dest,
+            &DeployOptions::default(),
+            &mut PendingTriggers::default(),
+            &|_| true,
+            |_| Ok(()),
+        );
+        assert!(res.is_err());
+
+        let left: Vec<_> = std::fs::read_dir(&dest)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(left, vec![std::ffi::OsString::from("file")]);
+    }
+}
@@ -1,89 +1,315 @@
 //! This module groups utility functions for interacting with the filesystem
-use crate::error::{LError, LErrorExt};
+use crate::error::{LError, LErrorClass, LErrorExt};
+use filetime::FileTime;
 use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::Permissions,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    },
     path::{Path, PathBuf},
     slice::Iter,
 };
 
-use crate::util;
+use crate::{
+    config::{Config, DeployStrategy},
+    util::{self, triggers::PendingTriggers},
+};
+
+/// The different types of filesystem entries leaf can handle
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FSEntryType {
+    File,
+    Directory,
+    Symlink,
+    /// A hard link to another file of the same package, see `FSEntry::link_target`
+    HardLink,
+    CharDevice,
+    BlockDevice,
+    FIFO,
+    Socket,
+}
 
 /// Represents a filesystem entry stored in the database
 #[derive(Clone, Debug)]
 pub struct FSEntry {
     /// The name of the entry without its path
     pub name: String,
-    /// The hash for the file, None if directory
+    /// The type of this entry
+    pub entry_type: FSEntryType,
+    /// The hash for the file, None if directory or special file
     pub hash: Option<String>,
     /// If this is a directory, the children are stored here
     pub children: Vec<FSEntry>,
+    /// The file mode (permission bits including setuid, setgid and sticky)
+    pub mode: u32,
+    /// The user id of the owner
+    pub uid: u32,
+    /// The group id of the owner
+    pub gid: u32,
+    /// The modification time in seconds since the UNIX epoch
+    pub mtime: i64,
+    /// For hard links, the path of the linked file relative to the package root
+    pub link_target: Option<String>,
+    /// For device nodes, the device id
+    pub rdev: u64,
 }
 
-impl FSEntry {
-    /// Indexes the supplied path recursively to this filesystem entry if both are a directory
-    /// creating new FSEntry objects on the way and building a filesystem tree
+/// Options controlling how entries get placed into their destination
+#[derive(Clone, Debug)]
+pub struct DeployOptions {
+    /// If files and their directories should be synced to disk before and after
+    /// renaming them into place
+    pub fsync: bool,
+    /// How files should be placed into their destination, only used by `copy_recursive()`:
+    /// Streaming deployment always writes the files from the archive
+    pub strategy: DeployStrategy,
+}
+
+impl Default for DeployOptions {
+    fn default() -> Self {
+        Self {
+            fsync: true,
+            strategy: DeployStrategy::Copy,
+        }
+    }
+}
+
+impl DeployOptions {
+    /// Creates the deploy options matching the supplied config
     /// # Arguments
-    /// * `directory` - The directory to index into the filesystem entry
-    fn index(&mut self, directory: &Path) -> Result<(), LError> {
-        if self.hash.is_none() {
-            self.children = index(directory)?;
+    /// * `config` - The config to derive the options from
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            fsync: config.fsync.unwrap_or(true),
+            strategy: config.deploy_strategy,
         }
-        Ok(())
     }
+}
 
+impl FSEntry {
     /// Appends the supplied string to create a recursive tree
     /// # Arguments
     /// * `depth` - The starting depth, should be 1 for a nice tree
     /// * `string` - A mutable reference to the string to append to
     pub fn print(&self, depth: usize, string: &mut String) {
         let msg = " |".repeat(depth);
-        match &self.hash {
-            Some(hash) => {
+        match (&self.entry_type, &self.hash) {
+            (FSEntryType::Directory, _) => string.push_str(&format!("\n{}_ {}/", &msg, self.name)),
+            (_, Some(hash)) => {
                 let msg = format!("{}_ {}", &msg, self.name);
                 string.push_str(&format!("\n{:.<50}{}", &msg, hash));
             }
-            None => string.push_str(&format!("\n{}_ {}/", &msg, self.name)),
+            (entry_type, None) => {
+                let msg = format!("{}_ {}", &msg, self.name);
+                string.push_str(&format!("\n{:.<50}{}", &msg, entry_type));
+            }
         }
 
         for child in &self.children {
             child.print(depth + 1, string);
         }
     }
+
+    /// Checks if the file at the supplied path already has the permissions, modification time
+    /// and, if leaf runs as root, the ownership of this entry
+    /// # Arguments
+    /// * `path` - The path to check
+    pub fn metadata_matches(&self, path: &Path) -> Result<bool, LError> {
+        let metadata = std::fs::symlink_metadata(path).err_prepend(&format!(
+            "When reading metadata of {}",
+            path.to_string_lossy()
+        ))?;
+
+        let owner_matches =
+            !util::running_as_root() || (metadata.uid() == self.uid && metadata.gid() == self.gid);
+
+        Ok(
+            owner_matches
+                && metadata.mode() & 0o7777 == self.mode
+                && metadata.mtime() == self.mtime,
+        )
+    }
+
+    /// Applies the ownership, permissions and modification time of this entry to the supplied path
+    ///
+    /// Ownership is only applied if leaf runs as root, symlinks only receive ownership and mtime
+    /// # Arguments
+    /// * `path` - The path to apply the metadata to
+    pub fn apply_metadata(&self, path: &Path) -> Result<(), LError> {
+        let msg = format!("When applying metadata to {}", path.to_string_lossy());
+        let mtime = FileTime::from_unix_time(self.mtime, 0);
+        let is_symlink = self.entry_type == FSEntryType::Symlink;
+
+        // Changing the owner clears the setuid and setgid bits, so do that first
+        if util::running_as_root() {
+            trace!(
+                "Changing owner of {} to {}:{}",
+                path.to_string_lossy(),
+                self.uid,
+                self.gid
+            );
+            if is_symlink {
+                std::os::unix::fs::lchown(path, Some(self.uid), Some(self.gid))
+                    .err_prepend(&msg)?;
+            } else {
+                std::os::unix::fs::chown(path, Some(self.uid), Some(self.gid)).err_prepend(&msg)?;
+            }
+        }
+
+        if !is_symlink {
+            trace!(
+                "Setting mode of {} to {:o}",
+                path.to_string_lossy(),
+                self.mode
+            );
+            std::fs::set_permissions(path, Permissions::from_mode(self.mode)).err_prepend(&msg)?;
+        }
+
+        // This does not follow symlinks nor open the path, which would block on FIFOs
+        filetime::set_symlink_file_times(path, mtime, mtime).err_prepend(&msg)?;
+
+        Ok(())
+    }
+}
+
+impl FSEntryType {
+    /// Returns the string representation of this type used in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::File => "file",
+            Self::Directory => "directory",
+            Self::Symlink => "symlink",
+            Self::HardLink => "hardlink",
+            Self::CharDevice => "chardevice",
+            Self::BlockDevice => "blockdevice",
+            Self::FIFO => "fifo",
+            Self::Socket => "socket",
+        }
+    }
+
+    /// Returns the `S_IFMT` bits for entry types that get created using `mknod()`
+    fn mknod_type(&self) -> Option<libc::mode_t> {
+        match self {
+            Self::CharDevice => Some(libc::S_IFCHR),
+            Self::BlockDevice => Some(libc::S_IFBLK),
+            Self::FIFO => Some(libc::S_IFIFO),
+            Self::Socket => Some(libc::S_IFSOCK),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for FSEntryType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
 /// Indexes the supplied directory into a vector of FSEntries
 ///
 /// The entries in the directory are not wrapped into a FSEntry, but rather
-/// are returned in the `Vec<FSEntry>` result of this function
+/// are returned in the `Vec<FSEntry>` result of this function.
+///
+/// The entries of every directory are sorted by name. Files that share their inode
+/// with an already indexed file are indexed as hard links pointing to the first occurrence.
 /// # Arguments
 /// * `directory` - The directory to index recursively
 pub fn index(directory: &Path) -> Result<Vec<FSEntry>, LError> {
+    let mut inodes: HashMap<(u64, u64), (String, Option<String>)> = HashMap::new();
+    index_rec(directory, Path::new(""), &mut inodes)
+}
+
+/// The recursive version of index()
+/// # Arguments
+/// * `directory` - The directory to index recursively
+/// * `relative` - The path of `directory` relative to the indexing root
+/// * `inodes` - The already indexed inodes with their relative path and hash
+fn index_rec(
+    directory: &Path,
+    relative: &Path,
+    inodes: &mut HashMap<(u64, u64), (String, Option<String>)>,
+) -> Result<Vec<FSEntry>, LError> {
     let mut res: Vec<FSEntry> = Vec::new();
 
-    for entry in std::fs::read_dir(directory)? {
-        let entry = entry?;
+    // Sort the entries to get the same tree (and hard link targets) for the same contents
+    let mut entries = std::fs::read_dir(directory)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
         let path = entry.path();
-        let isdir = path.is_dir();
-        let mut new_entry = FSEntry {
-            name: entry.file_name().to_string_lossy().to_string(),
-            children: Vec::new(),
-            // Compute the hash:
-            // directory:   None
-            // link:        Target
-            // file:        File
-            hash: {
-                if path.is_symlink() {
-                    let target_path = path.read_link()?;
-                    Some(util::hash::hash_str(&target_path.to_string_lossy()))
-                } else if isdir {
-                    None
-                } else {
-                    Some(util::hash::hash_file(&path)?)
+        let relative = relative.join(entry.file_name());
+        let metadata = entry.metadata()?;
+        let file_type = metadata.file_type();
+
+        let mut entry_type = if file_type.is_symlink() {
+            FSEntryType::Symlink
+        } else if file_type.is_dir() {
+            FSEntryType::Directory
+        } else if file_type.is_char_device() {
+            FSEntryType::CharDevice
+        } else if file_type.is_block_device() {
+            FSEntryType::BlockDevice
+        } else if file_type.is_fifo() {
+            FSEntryType::FIFO
+        } else if file_type.is_socket() {
+            FSEntryType::Socket
+        } else {
+            FSEntryType::File
+        };
+
+        // Compute the hash:
+        // directory:   None
+        // link:        Target
+        // file:        File
+        // hard link:   The linked file
+        // special:     None
+        let mut link_target = None;
+        let hash = match entry_type {
+            FSEntryType::Symlink => {
+                let target_path = path.read_link()?;
+                Some(util::hash::hash_str(&target_path.to_string_lossy()))
+            }
+            FSEntryType::File if metadata.nlink() > 1 => {
+                match inodes.get(&(metadata.dev(), metadata.ino())) {
+                    Some((target, hash)) => {
+                        entry_type = FSEntryType::HardLink;
+                        link_target = Some(target.clone());
+                        hash.clone()
+                    }
+                    None => {
+                        let hash = Some(util::hash::hash_file(&path)?);
+                        inodes.insert(
+                            (metadata.dev(), metadata.ino()),
+                            (relative.to_string_lossy().to_string(), hash.clone()),
+                        );
+                        hash
+                    }
                 }
-            },
+            }
+            FSEntryType::File => Some(util::hash::hash_file(&path)?),
+            _ => None,
+        };
+
+        let children = match entry_type {
+            FSEntryType::Directory => index_rec(&path, &relative, inodes)?,
+            _ => Vec::new(),
         };
 
-        new_entry.index(&path)?;
-        res.push(new_entry);
+        res.push(FSEntry {
+            name: entry.file_name().to_string_lossy().to_string(),
+            entry_type,
+            hash,
+            children,
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mtime: metadata.mtime(),
+            link_target,
+            rdev: metadata.rdev(),
+        });
     }
 
     Ok(res)
@@ -92,6 +318,17 @@ pub fn index(directory: &Path) -> Result<Vec<FSEntry>, LError> {
 /// Copies the supplied iterator of FSEntries from the `src` directory to the `dest`
 /// directory recursively iterating over all the children.
 ///
+/// Placed entries receive the ownership, permissions and modification time stored in
+/// their FSEntry. Directories that already exist (e.g. `/usr` or `/etc`) are shared
+/// with the system and other packages, they keep their metadata.
+/// Hard links are recreated relative to the initial `dest` directory,
+/// special files (devices, FIFOs and sockets) are created using `mknod()`.
+///
+/// Every non-directory entry is created at a temporary name in its destination directory
+/// and then renamed over the destination, so an existing file never disappears during
+/// the replacement. If `options.fsync` is set, files are synced before and their
+/// directories after renaming.
+///
 /// The provided arguments must be mutable due to them getting modified during the
 /// copy process.
 /// Once the function exits they are restored to their original value.
@@ -99,17 +336,26 @@ pub fn index(directory: &Path) -> Result<Vec<FSEntry>, LError> {
 /// * `src` - The source root directory
 /// * `dest` - The destination root directory
 /// * `iter` - The iterator of FSEntries to copy
+/// * `options` - The options for placing the entries
+/// * `triggers` - The pending triggers to activate for every placed entry
 /// * `file_exists_handler` - A handler that gets called if the entry does already exist, true indicates overwrite
 /// # Example
 /// ```
-/// use leaf::util::fs::*;
+/// use leaf::util::{fs::*, triggers::PendingTriggers};
 /// use std::path::PathBuf;
 ///
 /// //Create an entry for testing
 /// let entry = FSEntry {
 ///     name: "test".to_string(),
+///     entry_type: FSEntryType::Directory,
 ///     hash: None,
-///     children: Vec::new()
+///     children: Vec::new(),
+///     mode: 0o755,
+///     uid: 0,
+///     gid: 0,
+///     mtime: 0,
+///     link_target: None,
+///     rdev: 0,
 /// };
 ///
 /// // The entry must be wrapped in a iterator
@@ -119,24 +365,65 @@ pub fn index(directory: &Path) -> Result<Vec<FSEntry>, LError> {
 /// let mut src = PathBuf::from("./src");
 /// let mut dest = PathBuf::from("./dest");
 ///
-/// copy_recursive(&mut src, &mut dest, &mut entries.iter(), &|path| false).unwrap();
+/// let options = DeployOptions::default();
+/// let mut triggers = PendingTriggers::default();
+///
+/// copy_recursive(&mut src, &mut dest, &mut entries.iter(), &options, &mut triggers, &|path| false).unwrap();
 /// ```
 pub fn copy_recursive<F>(
     src: &mut PathBuf,
     dest: &mut PathBuf,
     iter: &mut Iter<FSEntry>,
+    options: &DeployOptions,
+    triggers: &mut PendingTriggers,
     file_exists_handler: &F,
 ) -> Result<(), LError>
 where
     F: Fn(&Path) -> bool,
 {
+    let dest_root = dest.clone();
+    copy_recursive_rec(
+        src,
+        dest,
+        &dest_root,
+        iter,
+        options,
+        triggers,
+        file_exists_handler,
+    )
+}
+
+/// The recursive version of copy_recursive()
+/// # Arguments
+/// * `src` - The source directory
+/// * `dest` - The destination directory
+/// * `dest_root` - The destination root directory hard links are relative to
+/// * `iter` - The iterator of FSEntries to copy
+/// * `options` - The options for placing the entries
+/// * `triggers` - The pending triggers to activate for every placed entry
+/// * `file_exists_handler` - A handler that gets called if the entry does already exist, true indicates overwrite
+fn copy_recursive_rec<F>(
+    src: &mut PathBuf,
+    dest: &mut PathBuf,
+    dest_root: &Path,
+    iter: &mut Iter<FSEntry>,
+    options: &DeployOptions,
+    triggers: &mut PendingTriggers,
+    file_exists_handler: &F,
+) -> Result<(), LError>
+where
+    F: Fn(&Path) -> bool,
+{
+    let mut renamed = false;
+
     for entry in iter {
         src.push(&entry.name);
         dest.push(&entry.name);
 
-        if entry.hash.is_none() {
+        if entry.entry_type == FSEntryType::Directory {
             // If the destination directory does not exist, create it
-            if !dest.exists() {
+            let created = !dest.exists();
+            if created {
                 trace!("Creating directory {}", dest.to_string_lossy());
                 std::fs::create_dir_all(&dest).err_append(&format!(
                     "When creating directory {}",
@@ -145,7 +432,20 @@ where
             }
 
             // And copy the directory contents, too
-            copy_recursive(src, dest, &mut entry.children.iter(), file_exists_handler)?;
+            copy_recursive_rec(
+                src,
+                dest,
+                dest_root,
+                &mut entry.children.iter(),
+                options,
+                triggers,
+                file_exists_handler,
+            )?;
+
+            // Apply the metadata after the children, copying them changes the mtime
+            if created {
+                entry.apply_metadata(dest)?;
+            }
         } else {
             // If the destination exists, call the callback
             if dest.is_symlink() || dest.exists() {
@@ -157,33 +457,354 @@ where
                     ));
                 }
                 warn!("Overwriting destination at {:?}", dest);
-                std::fs::remove_file(&dest).err_prepend("When removing file")?;
             }
 
-            // If the source is a symlink, create it in the destination
-            if src.is_symlink() {
-                let symlink_dest = src.read_link()?;
-                let msg = format!(
-                    "Creating symlink {} pointing to {}",
-                    dest.to_string_lossy(),
-                    symlink_dest.to_string_lossy()
-                );
-                trace!("{}", &msg);
-                std::os::unix::fs::symlink(symlink_dest, &dest).err_append(&msg)?;
-            } else {
-                // Else just copy the file
-                let msg = format!(
-                    "Copying {} ==> {}",
-                    src.to_string_lossy(),
-                    dest.to_string_lossy()
-                );
-                trace!("{}", &msg);
-                std::fs::copy(&src, &dest).err_append(&msg)?;
+            // Create the entry at a temporary path, which gets removed again if that fails
+            let temp_file = TempFile::new(dest);
+            let temp = temp_file.path().to_path_buf();
+            if temp.is_symlink() || temp.exists() {
+                std::fs::remove_file(&temp).err_prepend("When removing stale temporary file")?;
+            }
+
+            match entry.entry_type {
+                // If the source is a symlink, create it in the destination
+                FSEntryType::Symlink => {
+                    let symlink_dest = src.read_link()?;
+                    let msg = format!(
+                        "Creating symlink {} pointing to {}",
+                        dest.to_string_lossy(),
+                        symlink_dest.to_string_lossy()
+                    );
+                    trace!("{}", &msg);
+                    std::os::unix::fs::symlink(symlink_dest, &temp).err_append(&msg)?;
+                    entry.apply_metadata(&temp)?;
+                }
+                // Hard links share the metadata of their target, no need to apply it
+                FSEntryType::HardLink => {
+                    let target = match &entry.link_target {
+                        Some(target) => dest_root.join(target),
+                        None => {
+                            return Err(LError::new(
+                                LErrorClass::IO(std::io::ErrorKind::InvalidData),
+                                &format!("Hard link {} has no target", dest.to_string_lossy()),
+                            ))
+                        }
+                    };
+                    let msg = format!(
+                        "Creating hard link {} pointing to {}",
+                        dest.to_string_lossy(),
+                        target.to_string_lossy()
+                    );
+                    trace!("{}", &msg);
+                    std::fs::hard_link(&target, &temp).err_append(&msg)?;
+                }
+                FSEntryType::CharDevice
+                | FSEntryType::BlockDevice
+                | FSEntryType::FIFO
+                | FSEntryType::Socket => {
+                    let msg = format!("Creating {} {}", entry.entry_type, dest.to_string_lossy());
+                    trace!("{}", &msg);
+                    mknod(&temp, entry).err_append(&msg)?;
+                    entry.apply_metadata(&temp)?;
+                }
+                // Else place the file using the configured strategy
+                _ => {
+                    // A hard link shares the inode with the package cache, so its metadata
+                    // must not be changed: Copy files whose metadata differs instead
+                    let strategy = match options.strategy {
+                        DeployStrategy::HardLink if !entry.metadata_matches(src)? => {
+                            trace!(
+                                "Metadata of {} differs from the package, copying it",
+                                src.to_string_lossy()
+                            );
+                            DeployStrategy::Copy
+                        }
+                        strategy => strategy,
+                    };
+                    let msg = format!(
+                        "Placing {} ==> {} ({:?})",
+                        src.to_string_lossy(),
+                        dest.to_string_lossy(),
+                        strategy
+                    );
+                    trace!("{}", &msg);
+                    if place_file(src, &temp, strategy).err_append(&msg)?
+                        != DeployStrategy::HardLink
+                    {
+                        entry.apply_metadata(&temp)?;
+                    }
+                    if options.fsync {
+                        sync_path(&temp).err_append(&msg)?;
+                    }
+                }
+            }
+
+            // And atomically replace the destination
+            temp_file.persist(dest).err_append(&format!(
+                "When renaming {} to {}",
+                temp.to_string_lossy(),
+                dest.to_string_lossy()
+            ))?;
+            renamed = true;
+            if let Ok(relative) = dest.strip_prefix(dest_root) {
+                triggers.collect(relative);
+            }
+
+            // Renaming a hard link onto the same file is a no-op, leaving the temporary link behind
+            if temp.is_symlink() || temp.exists() {
+                std::fs::remove_file(&temp).err_prepend("When removing temporary file")?;
             }
         }
 
         src.pop();
         dest.pop();
     }
+
+    // Make sure the renames in this directory hit the disk
+    if renamed && options.fsync {
+        sync_path(dest).err_append(&format!(
+            "When syncing directory {}",
+            dest.to_string_lossy()
+        ))?;
+    }
+
     Ok(())
 }
+
+/// Places the file at `src` at `dest` using the supplied strategy
+/// and returns the strategy that has actually been used
+///
+/// If hard linking or reflinking is not possible, this falls back to copying the file
+/// # Arguments
+/// * `src` - The file to place
+/// * `dest` - The path to place the file at, must not exist
+/// * `strategy` - The strategy to use
+pub(crate) fn place_file(
+    src: &Path,
+    dest: &Path,
+    strategy: DeployStrategy,
+) -> Result<DeployStrategy, std::io::Error> {
+    match strategy {
+        DeployStrategy::Copy => {}
+        DeployStrategy::HardLink => match std::fs::hard_link(src, dest) {
+            Ok(_) => return Ok(strategy),
+            Err(e) => trace!("Hard linking failed ({}), falling back to copy", e),
+        },
+        DeployStrategy::Reflink => {
+            return match copy_file(src, dest)? {
+                true => Ok(strategy),
+                false => Ok(DeployStrategy::Copy),
+            }
+        }
+    }
+
+    std::fs::copy(src, dest)?;
+    Ok(DeployStrategy::Copy)
+}
+
+/// Copies the file at `src` to `dest`, reflinking it if the filesystem supports it.
+/// Unlike a hard link, the copy never shares its inode with `src`
+///
+/// Returns whether the file has been reflinked
+/// # Arguments
+/// * `src` - The file to copy
+/// * `dest` - The path to copy the file to, must not exist
+pub(crate) fn copy_file(src: &Path, dest: &Path) -> Result<bool, std::io::Error> {
+    match reflink(src, dest) {
+        Ok(_) => return Ok(true),
+        Err(e) => {
+            trace!("Reflinking failed ({}), falling back to copy", e);
+            if dest.exists() {
+                std::fs::remove_file(dest)?;
+            }
+        }
+    }
+
+    std::fs::copy(src, dest)?;
+    Ok(false)
+}
+
+/// Clones the file at `src` to `dest` using the `FICLONE` ioctl, sharing the data blocks
+/// # Arguments
+/// * `src` - The file to clone
+/// * `dest` - The path of the new file, must not exist
+fn reflink(src: &Path, dest: &Path) -> Result<(), std::io::Error> {
+    use std::os::fd::AsRawFd;
+
+    let src_file = std::fs::File::open(src)?;
+    let dest_file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dest)?;
+
+    match unsafe { libc::ioctl(dest_file.as_raw_fd(), libc::FICLONE, src_file.as_raw_fd()) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Syncs the file or directory at the supplied path to disk
+/// # Arguments
+/// * `path` - The path to sync
+pub(crate) fn sync_path(path: &Path) -> Result<(), std::io::Error> {
+    std::fs::File::open(path)?.sync_all()
+}
+
+/// Returns the temporary path next to `dest` leaf uses to create a new entry before renaming it into place
+///
+/// Example: `/usr/lib/libc.so.6` -> `/usr/lib/.libc.so.6.leaf-new`
+/// # Arguments
+/// * `dest` - The final path of the entry
+pub(crate) fn temp_path(dest: &Path) -> PathBuf {
+    let name = dest
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    dest.with_file_name(format!(".{}.leaf-new", name))
+}
+
+/// A temporary file next to its destination (see `temp_path()`) that gets removed when dropped,
+/// unless it has been renamed into place using `persist()`
+pub(crate) struct TempFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    /// Creates the guard for the temporary file of the supplied destination,
+    /// the file itself is created by the caller
+    /// # Arguments
+    /// * `dest` - The final path of the file
+    pub(crate) fn new(dest: &Path) -> Self {
+        Self {
+            path: temp_path(dest),
+            persisted: false,
+        }
+    }
+
+    /// Returns the path of the temporary file
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Renames the temporary file to `dest`
+    /// # Arguments
+    /// * `dest` - The final path of the file
+    pub(crate) fn persist(mut self, dest: &Path) -> Result<(), std::io::Error> {
+        std::fs::rename(&self.path, dest)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted && (self.path.is_symlink() || self.path.exists()) {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                warn!(
+                    "Failed to remove temporary file {}: {}",
+                    self.path.to_string_lossy(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Creates the special file described by the supplied entry at `path` using `mknod()`
+/// # Arguments
+/// * `path` - The path to create the special file at
+/// * `entry` - The entry describing the special file
+pub(crate) fn mknod(path: &Path, entry: &FSEntry) -> Result<(), std::io::Error> {
+    let file_type = match entry.entry_type.mknod_type() {
+        Some(t) => t,
+        None => return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
+    };
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mode = file_type | (entry.mode & 0o7777) as libc::mode_t;
+
+    match unsafe { libc::mknod(c_path.as_ptr(), mode, entry.rdev as libc::dev_t) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_recursive_leaves_existing_directories_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dest = dir.path().join("dest");
+        std::fs::create_dir_all(src.join("tmp")).unwrap();
+        std::fs::write(src.join("tmp/file"), "data").unwrap();
+        std::fs::create_dir_all(src.join("usr")).unwrap();
+        std::fs::create_dir_all(dest.join("usr")).unwrap();
+        std::fs::set_permissions(dest.join("usr"), Permissions::from_mode(0o755)).unwrap();
+
+        let mut entries = index(&src).unwrap();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries[0].mode = 0o1777;
+        entries[1].mode = 0o700;
+
+        copy_recursive(
+            &mut src.clone(),
+            &mut dest.clone(),
+            &mut entries.iter(),
+            &DeployOptions::default(),
+            &mut PendingTriggers::default(),
+            &|_| false,
+        )
+        .unwrap();
+
+        let mode = |path: &str| std::fs::metadata(dest.join(path)).unwrap().mode() & 0o7777;
+        assert_eq!(mode("tmp"), 0o1777);
+        assert_eq!(mode("usr"), 0o755);
+        assert_eq!(std::fs::read(dest.join("tmp/file")).unwrap(), b"data");
+    }
+
+    #[test]
+    fn hard_link_strategy_keeps_metadata_of_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dest = dir.path().join("dest");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir_all(&dest).unwrap();
+        std::fs::write(src.join("same"), "same").unwrap();
+        std::fs::write(src.join("differs"), "differs").unwrap();
+        for name in ["same", "differs"] {
+            std::fs::set_permissions(src.join(name), Permissions::from_mode(0o644)).unwrap();
+        }
+
+        let mut entries = index(&src).unwrap();
+        entries
+            .iter_mut()
+            .find(|e| e.name == "differs")
+            .unwrap()
+            .mode = 0o600;
+
+        let options = DeployOptions {
+            fsync: false,
+            strategy: DeployStrategy::HardLink,
+        };
+        copy_recursive(
+            &mut src.clone(),
+            &mut dest.clone(),
+            &mut entries.iter(),
+            &options,
+            &mut PendingTriggers::default(),
+            &|_| false,
+        )
+        .unwrap();
+
+        let ino = |path: &Path| std::fs::metadata(path).unwrap().ino();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().mode() & 0o7777;
+        assert_eq!(ino(&src.join("same")), ino(&dest.join("same")));
+        assert_ne!(ino(&src.join("differs")), ino(&dest.join("differs")));
+        assert_eq!(mode(&src.join("differs")), 0o644);
+        assert_eq!(mode(&dest.join("differs")), 0o600);
+    }
+}
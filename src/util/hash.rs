@@ -1,42 +1,171 @@
 //! This module groups utility functions for using the hashing algorithms
-use md5::*;
+//!
+//! Hashes are represented as strings tagged with their algorithm: `<algorithm>:<hex digest>`
+//! (e.g. `sha256:2c26b4...`). Untagged hashes are treated as MD5 for compatibility.
+use md5::{Digest, Md5};
+use serde::{Deserialize, Deserializer};
+use sha2::Sha256;
 
-use crate::error::LError;
+use crate::error::{LError, LErrorClass};
+use std::io::{Read, Write};
 use std::path::Path;
+use std::str::FromStr;
 
-/// Computes the MD5 hash of the source string
+/// The hashing algorithms leaf can use
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HashAlgorithm {
+    MD5,
+    #[default]
+    SHA256,
+    BLAKE3,
+}
+
+impl HashAlgorithm {
+    /// Returns the tag of this algorithm used in hash strings and the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::MD5 => "md5",
+            Self::SHA256 => "sha256",
+            Self::BLAKE3 => "blake3",
+        }
+    }
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = LError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "md5" => Ok(Self::MD5),
+            "sha256" => Ok(Self::SHA256),
+            "blake3" => Ok(Self::BLAKE3),
+            s => Err(LError::new(
+                LErrorClass::Unknown,
+                &format!("Unknown hash algorithm '{}'", s),
+            )),
+        }
+    }
+}
+
+/// A running hash computation using one of the supported algorithms
+enum Hasher {
+    MD5(Md5),
+    SHA256(Sha256),
+    BLAKE3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::MD5 => Self::MD5(Md5::new()),
+            HashAlgorithm::SHA256 => Self::SHA256(Sha256::new()),
+            HashAlgorithm::BLAKE3 => Self::BLAKE3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::MD5(h) => h.update(data),
+            Self::SHA256(h) => h.update(data),
+            Self::BLAKE3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    /// Finalizes the computation and returns the tagged hash string
+    fn finalize(self) -> String {
+        let (algorithm, digest) = match self {
+            Self::MD5(h) => (HashAlgorithm::MD5, h.finalize().to_vec()),
+            Self::SHA256(h) => (HashAlgorithm::SHA256, h.finalize().to_vec()),
+            Self::BLAKE3(h) => (HashAlgorithm::BLAKE3, h.finalize().as_bytes().to_vec()),
+        };
+
+        tag_hash(algorithm, &base16ct::lower::encode_string(&digest))
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Joins the supplied algorithm and hex digest to a tagged hash string
+/// # Arguments
+/// * `algorithm` - The algorithm the digest has been computed with
+/// * `digest` - The hex digest
+pub fn tag_hash(algorithm: HashAlgorithm, digest: &str) -> String {
+    format!("{}:{}", algorithm, digest)
+}
+
+/// Splits the supplied hash string into its algorithm and hex digest,
+/// untagged hashes are MD5 hashes
+/// # Arguments
+/// * `hash` - The hash string to split
+pub fn split_hash(hash: &str) -> Result<(HashAlgorithm, &str), LError> {
+    match hash.split_once(':') {
+        Some((algorithm, digest)) => Ok((algorithm.parse()?, digest)),
+        None => Ok((HashAlgorithm::MD5, hash)),
+    }
+}
+
+/// Returns the tagged form of the supplied hash string
+/// # Arguments
+/// * `hash` - The tagged or untagged (MD5) hash
+pub fn normalize_hash(hash: &str) -> Result<String, LError> {
+    let (algorithm, digest) = split_hash(hash)?;
+    Ok(tag_hash(algorithm, &digest.to_lowercase()))
+}
+
+/// Deserializes a tagged or untagged (MD5) hash into its tagged form
+pub fn deserialize_hash<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let hash = String::deserialize(deserializer)?;
+    normalize_hash(&hash).map_err(serde::de::Error::custom)
+}
+
+/// Computes the hash of the source string using the default algorithm
 /// # Arguments
 /// * `source` - The string to hash
 pub fn hash_str(source: &str) -> String {
-    //Create the hasher and hash
-    let mut hasher = Md5::new();
-    hasher.update(source);
-
-    //Finalize and compute base16 string
-    let mut buf = [0u8; 32];
-    let hash = hasher.finalize();
-    let res = base16ct::lower::encode_str(&hash, &mut buf).expect("Convert hash to base16");
+    let mut hasher = Hasher::new(HashAlgorithm::default());
+    hasher.update(source.as_bytes());
+    let res = hasher.finalize();
 
     trace!("Computed hash of string '{}': {}", source, res);
 
-    res.to_string()
+    res
 }
 
-/// Computes the MD5 hash of the file supplied as source
+/// Computes the hash of the file supplied as source using the default algorithm
 /// # Arguments
 /// * `source` - The source file to hash
 pub fn hash_file(source: &Path) -> Result<String, LError> {
-    //Open the file
-    let mut file = std::fs::File::open(source)?;
-
-    //Create the hasher and hash
-    let mut hasher = Md5::new();
-    std::io::copy(&mut file, &mut hasher)?;
+    hash_file_with(source, HashAlgorithm::default())
+}
 
-    //Finalize and compute base16 string
-    let mut buf = [0u8; 32];
-    let hash = hasher.finalize();
-    let res = base16ct::lower::encode_str(&hash, &mut buf).expect("Convert hash to base16");
+/// Computes the hash of the file supplied as source using the supplied algorithm
+/// # Arguments
+/// * `source` - The source file to hash
+/// * `algorithm` - The algorithm to use
+pub fn hash_file_with(source: &Path, algorithm: HashAlgorithm) -> Result<String, LError> {
+    //Open the file and hash it
+    let mut file = std::fs::File::open(source)?;
+    let res = hash_reader_with(&mut file, algorithm)?;
 
     trace!(
         "Computed hash of file {}: {}",
@@ -44,5 +173,121 @@ pub fn hash_file(source: &Path) -> Result<String, LError> {
         res
     );
 
-    Ok(res.to_owned())
+    Ok(res)
+}
+
+/// Computes the hash of all data the supplied reader delivers using the supplied algorithm
+/// # Arguments
+/// * `source` - The reader to hash
+/// * `algorithm` - The algorithm to use
+pub fn hash_reader_with<R: Read>(
+    source: &mut R,
+    algorithm: HashAlgorithm,
+) -> Result<String, LError> {
+    let mut hasher = Hasher::new(algorithm);
+    std::io::copy(source, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Checks if the file supplied as source matches the expected hash,
+/// using the algorithm the expected hash has been computed with
+/// # Arguments
+/// * `source` - The source file to check
+/// * `expected` - The tagged or untagged (MD5) hash the file should have
+pub fn verify_file(source: &Path, expected: &str) -> Result<bool, LError> {
+    let expected = normalize_hash(expected)?;
+    let (algorithm, _) = split_hash(&expected)?;
+    Ok(hash_file_with(source, algorithm)? == expected)
+}
+
+/// Copies all data from `source` to `dest` while computing the hash of it using the default algorithm
+/// # Arguments
+/// * `source` - The reader to copy from
+/// * `dest` - The writer to copy to
+pub fn hash_copy<R: Read, W: Write>(source: &mut R, dest: &mut W) -> Result<String, LError> {
+    let mut hasher = Hasher::new(HashAlgorithm::default());
+    let mut data = [0u8; 8192];
+
+    loop {
+        let len = source.read(&mut data)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&data[..len]);
+        dest.write_all(&data[..len])?;
+    }
+
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tagged_hashes_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"leaf").unwrap();
+
+        for algorithm in [
+            HashAlgorithm::MD5,
+            HashAlgorithm::SHA256,
+            HashAlgorithm::BLAKE3,
+        ] {
+            let hash = hash_file_with(&file, algorithm).unwrap();
+            let (parsed, digest) = split_hash(&hash).unwrap();
+
+            assert_eq!(parsed, algorithm);
+            assert_eq!(tag_hash(parsed, digest), hash);
+            assert_eq!(
+                normalize_hash(&tag_hash(algorithm, &digest.to_uppercase())).unwrap(),
+                hash
+            );
+            assert!(verify_file(&file, &hash).unwrap());
+        }
+    }
+
+    #[test]
+    fn untagged_hashes_are_md5() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"leaf").unwrap();
+
+        let hash = hash_file_with(&file, HashAlgorithm::MD5).unwrap();
+        let (_, digest) = split_hash(&hash).unwrap();
+
+        assert_eq!(normalize_hash(&digest.to_uppercase()).unwrap(), hash);
+        assert!(verify_file(&file, digest).unwrap());
+    }
+
+    #[test]
+    fn modified_files_do_not_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"leaf").unwrap();
+        let hash = hash_file(&file).unwrap();
+
+        std::fs::write(&file, b"leaves").unwrap();
+
+        assert!(!verify_file(&file, &hash).unwrap());
+    }
+
+    #[test]
+    fn unknown_algorithms_are_refused() {
+        assert!(split_hash("sha1:a9993e36").is_err());
+    }
+
+    #[test]
+    fn hash_copy_matches_hash_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"leaf").unwrap();
+
+        let mut copy = Vec::new();
+        let hash = hash_copy(&mut &b"leaf"[..], &mut copy).unwrap();
+
+        assert_eq!(copy, b"leaf");
+        assert_eq!(hash, hash_file(&file).unwrap());
+    }
 }
@@ -0,0 +1,187 @@
+//! This module provides the execution of package scripts (install hooks)
+use crate::{config::Config, error::*};
+use std::{
+    ffi::{CString, OsStr},
+    os::unix::{ffi::OsStrExt, process::CommandExt},
+    process::{Command, Stdio},
+};
+
+/// The points in the lifecycle of a package a script can hook into
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScriptHook {
+    PreInstall,
+    PostInstall,
+}
+
+impl ScriptHook {
+    /// Returns the file name of the script for this hook inside the `scripts/`
+    /// directory of a package (next to `data/`)
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Self::PreInstall => "preinstall",
+            Self::PostInstall => "postinstall",
+        }
+    }
+}
+
+impl std::fmt::Display for ScriptHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.file_name())
+    }
+}
+
+/// Runs the supplied script using `/bin/sh` with the configured root as the working directory
+///
+/// If the root is not `/`, the script gets executed chrooted into the root.
+/// The script is passed to the shell as an argument (`sh -c`), so it does not need to exist
+/// inside the root. Its stdin is `/dev/null`.
+/// The environment variables `LEAF_HOOK`, `LEAF_PACKAGE` and `LEAF_VERSION` are set for the script.
+/// # Arguments
+/// * `config` - The config to use for getting the root
+/// * `script` - The contents of the script to run
+/// * `hook` - The hook the script is run for
+/// * `name` - The name of the package the script belongs to
+/// * `version` - The version of the package the script belongs to
+pub fn run_script(
+    config: &Config,
+    script: &[u8],
+    hook: ScriptHook,
+    name: &str,
+    version: &str,
+) -> Result<(), LError> {
+    run_shell(
+        config,
+        script,
+        &[
+            ("LEAF_HOOK", hook.file_name()),
+            ("LEAF_PACKAGE", name),
+            ("LEAF_VERSION", version),
+        ],
+        &format!("{} script of package {}-{}", hook, name, version),
+        LErrorClass::ScriptFailed,
+    )
+}
+
+/// Runs the supplied script using `/bin/sh` with the configured root as the working directory,
+/// chrooting into the root if it is not `/`
+/// # Arguments
+/// * `config` - The config to use for getting the root
+/// * `script` - The contents of the script to pass to the shell
+/// * `envs` - Additional environment variables to set for the script
+/// * `context` - A description of the script for messages
+/// * `class` - The error class to report if the script fails
+pub(crate) fn run_shell(
+    config: &Config,
+    script: &[u8],
+    envs: &[(&str, &str)],
+    context: &str,
+    class: LErrorClass,
+) -> Result<(), LError> {
+    let root = config.get_root();
+
+    // Pass the script as an argument: Commands reading stdin must not consume the script
+    let mut command = Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(OsStr::from_bytes(script))
+        .arg("leaf-script")
+        .envs(envs.iter().copied())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    if root == std::path::Path::new("/") {
+        command.current_dir(root);
+    } else {
+        debug!(
+            "Running {} chrooted into {}",
+            context,
+            root.to_string_lossy()
+        );
+        let c_root = CString::new(root.as_os_str().as_bytes())
+            .map_err(|e| LError::new(class, &format!("Invalid root for {}: {}", context, e)))?;
+        let c_slash = CString::new("/").expect("Static string without nul byte");
+        unsafe {
+            command.pre_exec(move || {
+                if libc::chroot(c_root.as_ptr()) != 0 || libc::chdir(c_slash.as_ptr()) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    // This drains stdout and stderr while the script runs, so it never blocks on a full pipe
+    info!("Running {}", context);
+    let output = command
+        .output()
+        .err_prepend(&format!("When running {}", context))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in stdout.lines().chain(stderr.lines()) {
+        debug!("[{}] {}", context, line);
+    }
+
+    if !output.status.success() {
+        return Err(LError::new(
+            class,
+            &format!(
+                "{} exited with {}: {}",
+                context,
+                output.status,
+                stderr.trim()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_reading_stdin_do_not_consume_the_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+
+        run_shell(
+            &Config::default(),
+            b"cat\nread line || true\necho done > \"$OUT\"\n",
+            &[("OUT", &out.to_string_lossy())],
+            "test script",
+            LErrorClass::ScriptFailed,
+        )
+        .unwrap();
+
+        assert_eq!(std::fs::read_to_string(out).unwrap(), "done\n");
+    }
+
+    #[test]
+    fn large_output_does_not_block() {
+        run_shell(
+            &Config::default(),
+            b"head -c 1048576 /dev/zero\nhead -c 1048576 /dev/zero >&2\n",
+            &[],
+            "test script",
+            LErrorClass::ScriptFailed,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn failing_script_returns_error() {
+        let err = run_shell(
+            &Config::default(),
+            b"echo broken >&2\nexit 3\n",
+            &[],
+            "test script",
+            LErrorClass::ScriptFailed,
+        )
+        .unwrap_err();
+
+        assert_eq!(err.class, LErrorClass::ScriptFailed);
+    }
+}
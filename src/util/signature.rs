@@ -0,0 +1,295 @@
+//! This module provides Ed25519 signatures for verifying the data leaf receives
+//!
+//! Keys and signatures are represented as hex strings: public and secret keys are 32 bytes,
+//! signatures 64 bytes long.
+use crate::error::*;
+use crate::util::hash::{self, HashAlgorithm};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Decodes the supplied hex string into an array of `N` bytes
+/// # Arguments
+/// * `hex` - The hex string to decode
+/// * `what` - A description of the decoded value for messages
+fn decode<const N: usize>(hex: &str, what: &str) -> Result<[u8; N], LError> {
+    let invalid = |reason: String| {
+        LError::new(
+            LErrorClass::InvalidSignature,
+            &format!("Invalid {} '{}': {}", what, hex, reason),
+        )
+    };
+
+    let bytes = base16ct::mixed::decode_vec(hex.trim()).map_err(|e| invalid(e.to_string()))?;
+    bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| invalid(format!("expected {} bytes, got {}", N, b.len())))
+}
+
+/// Parses the supplied hex encoded Ed25519 public key
+/// # Arguments
+/// * `key` - The hex encoded public key
+pub fn parse_public_key(key: &str) -> Result<VerifyingKey, LError> {
+    VerifyingKey::from_bytes(&decode(key, "public key")?).map_err(|e| {
+        LError::new(
+            LErrorClass::InvalidSignature,
+            &format!("Invalid public key '{}': {}", key, e),
+        )
+    })
+}
+
+/// Signs the supplied data using the hex encoded Ed25519 secret key and returns the hex encoded signature
+/// # Arguments
+/// * `data` - The data to sign
+/// * `secret_key` - The hex encoded secret key
+pub fn sign(data: &[u8], secret_key: &str) -> Result<String, LError> {
+    let key = SigningKey::from_bytes(&decode(secret_key, "secret key")?);
+    Ok(base16ct::lower::encode_string(&key.sign(data).to_bytes()))
+}
+
+/// Returns the hex encoded public key belonging to the supplied hex encoded secret key
+/// # Arguments
+/// * `secret_key` - The hex encoded secret key
+pub fn public_key(secret_key: &str) -> Result<String, LError> {
+    let key = SigningKey::from_bytes(&decode(secret_key, "secret key")?);
+    Ok(base16ct::lower::encode_string(
+        key.verifying_key().as_bytes(),
+    ))
+}
+
+/// Verifies that the supplied signature of `data` has been made by one of the supplied keys
+///
+/// Returns `LErrorClass::InvalidSignature` if no key matches
+/// # Arguments
+/// * `data` - The signed data
+/// * `signature` - The hex encoded signature
+/// * `keys` - The hex encoded public keys to trust
+pub fn verify(data: &[u8], signature: &str, keys: &[String]) -> Result<(), LError> {
+    let signature = Signature::from_bytes(&decode(signature, "signature")?);
+
+    for key in keys {
+        if parse_public_key(key)?
+            .verify_strict(data, &signature)
+            .is_ok()
+        {
+            trace!("Signature has been made by key {}", key);
+            return Ok(());
+        }
+    }
+
+    Err(LError::new(
+        LErrorClass::InvalidSignature,
+        "Signature does not match any of the trusted keys",
+    ))
+}
+
+/// Checks the supplied data against the signing policy:
+/// Signed data must be signed by one of the trusted keys, unsigned
+/// data (or data without trusted keys) is only accepted if `allow_unsigned` is set
+/// # Arguments
+/// * `data` - The data to check
+/// * `signature` - The hex encoded signature of the data, if any
+/// * `keys` - The hex encoded public keys to trust
+/// * `allow_unsigned` - If data that can't be verified should be accepted
+/// * `what` - A description of the data for messages
+pub fn check(
+    data: &[u8],
+    signature: Option<&str>,
+    keys: &[String],
+    allow_unsigned: bool,
+    what: &str,
+) -> Result<(), LError> {
+    match (signature, keys.is_empty()) {
+        (Some(signature), false) => {
+            verify(data, signature, keys).err_prepend(&format!("When verifying {}", what))
+        }
+        (signature, _) if allow_unsigned => {
+            match signature {
+                Some(_) => warn!("Accepting {} without verifying it: No trusted keys", what),
+                None => warn!("Accepting unsigned {}", what),
+            }
+            Ok(())
+        }
+        (None, _) => Err(LError::new(
+            LErrorClass::InvalidSignature,
+            &format!("Refusing unsigned {}", what),
+        )),
+        (Some(_), true) => Err(LError::new(
+            LErrorClass::InvalidSignature,
+            &format!("Refusing {}: No trusted keys to verify it with", what),
+        )),
+    }
+}
+
+/// Returns the path of the detached signature of the supplied file: `<file>.sig`
+/// # Arguments
+/// * `file` - The signed file
+pub fn signature_path(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".sig");
+    PathBuf::from(path)
+}
+
+/// Returns the url of the detached signature of the file at `url`, appending `.sig` to its path
+/// so a query or fragment stays in place (`https://host/foo.lfpkg?v=1` -> `https://host/foo.lfpkg.sig?v=1`)
+/// # Arguments
+/// * `url` - The url of the signed file, an absolute path is treated as a local file
+pub fn signature_url(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(mut parsed) => {
+            let path = format!("{}.sig", parsed.path());
+            parsed.set_path(&path);
+            parsed.to_string()
+        }
+        Err(_) => format!("{}.sig", url),
+    }
+}
+
+/// Returns the message a file signature is made over: The tagged SHA-256 hash of the file
+/// (e.g. `sha256:2c26b4...`), so large files never need to be held in memory
+/// # Arguments
+/// * `file` - The contents of the file to compute the message of
+pub fn file_message<R: Read>(file: &mut R) -> Result<String, LError> {
+    hash::hash_reader_with(file, HashAlgorithm::SHA256)
+}
+
+/// Signs the supplied file and writes the detached signature to `<file>.sig`
+/// # Arguments
+/// * `file` - The file to sign
+/// * `secret_key` - The hex encoded secret key
+pub fn sign_file(file: &Path, secret_key: &str) -> Result<(), LError> {
+    let signature = sign(file_message(&mut File::open(file)?)?.as_bytes(), secret_key)?;
+    std::fs::write(signature_path(file), signature)?;
+    Ok(())
+}
+
+/// Checks the file opened from `path` and its detached signature at `<path>.sig` against the
+/// signing policy, see `check()`
+///
+/// The file gets hashed through the supplied handle, which is rewound to its start afterwards:
+/// Reading from it yields the verified data, even if the file at `path` gets replaced meanwhile
+/// # Arguments
+/// * `path` - The path the file has been opened from
+/// * `file` - The opened file to check
+/// * `keys` - The hex encoded public keys to trust
+/// * `allow_unsigned` - If files that can't be verified should be accepted
+/// * `what` - A description of the file for messages
+pub fn check_file(
+    path: &Path,
+    file: &mut File,
+    keys: &[String],
+    allow_unsigned: bool,
+    what: &str,
+) -> Result<(), LError> {
+    let signature_path = signature_path(path);
+    let signature = match signature_path.exists() {
+        true => Some(std::fs::read_to_string(&signature_path)?),
+        false => None,
+    };
+
+    file.seek(SeekFrom::Start(0))?;
+    let message = file_message(file)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    check(
+        message.as_bytes(),
+        signature.as_deref(),
+        keys,
+        allow_unsigned,
+        what,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const OTHER_SECRET: &str = "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb";
+
+    #[test]
+    fn signatures_verify_with_the_matching_key() {
+        let signature = sign(b"data", SECRET).unwrap();
+        let keys = vec![
+            public_key(OTHER_SECRET).unwrap(),
+            public_key(SECRET).unwrap(),
+        ];
+
+        assert!(verify(b"data", &signature, &keys).is_ok());
+        assert!(check(b"data", Some(&signature), &keys, false, "data").is_ok());
+    }
+
+    #[test]
+    fn signatures_do_not_verify_with_other_keys_or_data() {
+        let signature = sign(b"data", SECRET).unwrap();
+        let keys = vec![public_key(OTHER_SECRET).unwrap()];
+
+        let err = verify(b"data", &signature, &keys).unwrap_err();
+        assert_eq!(err.class, LErrorClass::InvalidSignature);
+
+        let keys = vec![public_key(SECRET).unwrap()];
+        let err = verify(b"other data", &signature, &keys).unwrap_err();
+        assert_eq!(err.class, LErrorClass::InvalidSignature);
+
+        // Unsigned data being allowed does not accept bad signatures
+        assert!(check(b"other data", Some(&signature), &keys, true, "data").is_err());
+    }
+
+    #[test]
+    fn unsigned_data_follows_the_policy() {
+        let keys = vec![public_key(SECRET).unwrap()];
+
+        assert!(check(b"data", None, &keys, true, "data").is_ok());
+        assert!(check(b"data", None, &keys, false, "data").is_err());
+
+        // Without trusted keys, signed data can't be verified either
+        let signature = sign(b"data", SECRET).unwrap();
+        assert!(check(b"data", Some(&signature), &[], true, "data").is_ok());
+        assert!(check(b"data", Some(&signature), &[], false, "data").is_err());
+    }
+
+    #[test]
+    fn signature_urls_append_to_the_path() {
+        assert_eq!(
+            signature_url("https://mirror.example.org/foo-1.0.lfpkg"),
+            "https://mirror.example.org/foo-1.0.lfpkg.sig"
+        );
+        assert_eq!(
+            signature_url("https://mirror.example.org/get?name=foo#top"),
+            "https://mirror.example.org/get.sig?name=foo#top"
+        );
+        assert_eq!(
+            signature_url("file:///srv/foo%20bar.lfpkg"),
+            "file:///srv/foo%20bar.lfpkg.sig"
+        );
+        assert_eq!(signature_url("/srv/foo.lfpkg"), "/srv/foo.lfpkg.sig");
+    }
+
+    #[test]
+    fn malformed_keys_and_signatures_are_refused() {
+        assert!(parse_public_key("00").is_err());
+        assert!(parse_public_key("not hex").is_err());
+        assert!(sign(b"data", "00").is_err());
+
+        let keys = vec![public_key(SECRET).unwrap()];
+        assert!(verify(b"data", "00", &keys).is_err());
+    }
+
+    #[test]
+    fn file_signatures_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"data").unwrap();
+        let keys = vec![public_key(SECRET).unwrap()];
+
+        assert!(check_file(&file, &mut File::open(&file).unwrap(), &keys, false, "file").is_err());
+
+        sign_file(&file, SECRET).unwrap();
+        assert!(signature_path(&file).exists());
+        assert!(check_file(&file, &mut File::open(&file).unwrap(), &keys, false, "file").is_ok());
+
+        std::fs::write(&file, b"modified").unwrap();
+        assert!(check_file(&file, &mut File::open(&file).unwrap(), &keys, false, "file").is_err());
+    }
+}
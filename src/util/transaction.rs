@@ -3,6 +3,7 @@ use crate::db::*;
 use crate::error::*;
 use crate::package::*;
 use crate::usermsg;
+use crate::util::triggers::PendingTriggers;
 
 /// Installs the provided package using the supplied configuration.
 /// It recurses through all the dependencies and makes sure they are installed.
@@ -10,13 +11,15 @@ use crate::usermsg;
 /// * `package` - The package to install
 /// * `config` - The configuration to use for installing
 /// * `db_con` - The database connection to use for installing
+/// * `triggers` - The pending triggers to activate for the deployed files
 pub fn install_package(
     package: PackageRef,
     config: &Config,
     db_con: &mut DBConnection,
+    triggers: &mut PendingTriggers,
 ) -> Result<(), LError> {
     // Install the package
-    install_package_rec(package.clone(), config, db_con)?;
+    install_package_rec(package.clone(), config, db_con, triggers)?;
 
     // Then add the dependencies to the database
     let package_read = package.read().expect("Lock package mutex");
@@ -31,6 +34,7 @@ fn install_package_rec(
     package: PackageRef,
     config: &Config,
     db_con: &mut DBConnection,
+    triggers: &mut PendingTriggers,
 ) -> Result<(), LError> {
     // Check if not already locked, if so, there is nothing to be done here
     match package.try_write() {
@@ -56,13 +60,15 @@ fn install_package_rec(
     let mut package_write = package.write().expect("Lock package mutex for writing");
 
     for dependency in package_write.get_dependencies().get_resolved()? {
-        install_package_rec(dependency.clone(), config, db_con)?;
+        install_package_rec(dependency.clone(), config, db_con, triggers)?;
     }
 
     // Deploy the package
     let old_package = package_write.get_local()?.clone();
     usermsg!("Installing package {}", old_package.get_fq_name());
-    *package_write = PackageVariant::Installed(old_package.deploy(config)?);
+    let installed_package = old_package.deploy(config, triggers)?;
+    db_con.insert_package_files(&installed_package)?;
+    *package_write = PackageVariant::Installed(installed_package);
 
     drop(package_write);
 
@@ -0,0 +1,178 @@
+//! This module provides system-wide triggers that run once per transaction
+use crate::{config::Config, error::*, usererr, util};
+use glob::{MatchOptions, Pattern};
+use serde::Deserialize;
+use std::path::Path;
+
+/// A trigger runs a command once at the end of a transaction if
+/// at least one deployed path matched one of its globs
+#[derive(Clone, Debug, Deserialize)]
+pub struct Trigger {
+    /// The name of the trigger
+    pub name: String,
+    /// The globs of paths inside the root that activate this trigger (e.g. `/usr/lib/**`)
+    pub paths: Vec<String>,
+    /// The command to run using `/bin/sh`
+    pub command: String,
+}
+
+/// The triggers that got collected during a transaction
+#[derive(Debug, Default)]
+pub struct PendingTriggers {
+    triggers: Vec<(Trigger, Vec<Pattern>)>,
+    activated: Vec<bool>,
+}
+
+impl PendingTriggers {
+    /// Creates a new set of pending triggers from the triggers in the supplied config
+    /// # Arguments
+    /// * `config` - The config to take the trigger definitions from
+    pub fn new(config: &Config) -> Result<Self, LError> {
+        let mut triggers = Vec::new();
+
+        for trigger in &config.triggers {
+            let mut patterns = Vec::new();
+            for path in &trigger.paths {
+                patterns.push(Pattern::new(path).map_err(|e| {
+                    LError::new(
+                        LErrorClass::TriggerFailed,
+                        &format!(
+                            "Invalid path glob {} of trigger {}: {}",
+                            path, trigger.name, e
+                        ),
+                    )
+                })?);
+            }
+            triggers.push((trigger.clone(), patterns));
+        }
+
+        Ok(Self {
+            activated: vec![false; triggers.len()],
+            triggers,
+        })
+    }
+
+    /// Activates all triggers that match the supplied path
+    /// # Arguments
+    /// * `path` - The path of the deployed entry relative to the root
+    pub fn collect(&mut self, path: &Path) {
+        let path = Path::new("/").join(path);
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+
+        for (i, (trigger, patterns)) in self.triggers.iter().enumerate() {
+            if self.activated[i] {
+                continue;
+            }
+
+            if patterns.iter().any(|p| p.matches_path_with(&path, options)) {
+                debug!(
+                    "Path {} activated trigger {}",
+                    path.to_string_lossy(),
+                    trigger.name
+                );
+                self.activated[i] = true;
+            }
+        }
+    }
+
+    /// Returns the triggers that have been activated so far
+    pub fn get_activated(&self) -> Vec<&Trigger> {
+        self.triggers
+            .iter()
+            .zip(self.activated.iter())
+            .filter(|(_, activated)| **activated)
+            .map(|((trigger, _), _)| trigger)
+            .collect()
+    }
+
+    /// Runs every activated trigger once, in the order they are defined in.
+    /// The environment variable `LEAF_TRIGGER` is set to the name of the trigger.
+    /// # Arguments
+    /// * `config` - The config to use for running the triggers
+    pub fn run(self, config: &Config) -> Result<(), LError> {
+        for trigger in self.get_activated() {
+            util::scripts::run_shell(
+                config,
+                trigger.command.as_bytes(),
+                &[("LEAF_TRIGGER", &trigger.name)],
+                &format!("trigger {}", trigger.name),
+                LErrorClass::TriggerFailed,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the activated triggers once the operation that activated them is done, see `run()`
+    ///
+    /// The triggers run even if the operation failed: What it deployed before failing needs them
+    /// all the same. Its error takes precedence over the triggers failing, which only gets logged then
+    /// # Arguments
+    /// * `config` - The config to use for running the triggers
+    /// * `res` - The result of the operation
+    pub fn run_after<T>(self, config: &Config, res: Result<T, LError>) -> Result<T, LError> {
+        match res {
+            Ok(v) => self.run(config).map(|_| v),
+            Err(e) => {
+                if let Err(trigger_error) = self.run(config) {
+                    usererr!("Failed to run triggers: {}", trigger_error);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a config defining a trigger for `/usr/lib/**` that runs `command`
+    fn config(command: &str) -> Config {
+        Config {
+            render_bar: false,
+            triggers: vec![Trigger {
+                name: "ldconfig".to_string(),
+                paths: vec!["/usr/lib/**".to_string()],
+                command: command.to_string(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn triggers_run_after_a_failed_operation() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let config = config(&format!("touch '{}'", marker.to_string_lossy()));
+        let mut triggers = PendingTriggers::new(&config).unwrap();
+        triggers.collect(Path::new("usr/lib/libc.so.6"));
+
+        let err = triggers
+            .run_after::<()>(&config, Err(LError::new_class(LErrorClass::ScriptFailed)))
+            .unwrap_err();
+
+        assert_eq!(err.class, LErrorClass::ScriptFailed);
+        assert!(marker.exists());
+    }
+
+    #[test]
+    fn the_operation_error_takes_precedence() {
+        let config = config("exit 1");
+        let mut triggers = PendingTriggers::new(&config).unwrap();
+        triggers.collect(Path::new("usr/lib/libc.so.6"));
+
+        let err = triggers
+            .run_after::<()>(&config, Err(LError::new_class(LErrorClass::ScriptFailed)))
+            .unwrap_err();
+        assert_eq!(err.class, LErrorClass::ScriptFailed);
+
+        let mut triggers = PendingTriggers::new(&config).unwrap();
+        triggers.collect(Path::new("usr/lib/libc.so.6"));
+        let err = triggers.run_after(&config, Ok(())).unwrap_err();
+        assert_eq!(err.class, LErrorClass::TriggerFailed);
+    }
+}